@@ -0,0 +1,42 @@
+//! Header hashing throughput, one element per hash:
+//! `cargo bench -p btclib --bench hashing`
+
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+fn header_hashing(c: &mut Criterion) {
+    let mut header = ChainParams::mainnet().genesis_block().header;
+
+    let mut group = c.benchmark_group("header_hash");
+    group.throughput(Throughput::Elements(1));
+
+    // the previous implementation: CBOR, hex digest, hex decode
+    group.bench_function("cbor", |b| {
+        b.iter(|| {
+            header.nonce = header.nonce.wrapping_add(1);
+            Hash::hash(black_box(&header))
+        })
+    });
+
+    group.bench_function("consensus", |b| {
+        b.iter(|| {
+            header.nonce = header.nonce.wrapping_add(1);
+            black_box(&header).hash()
+        })
+    });
+
+    let midstate = header.midstate();
+    let mut nonce = 0u64;
+    group.bench_function("midstate", |b| {
+        b.iter(|| {
+            nonce = nonce.wrapping_add(1);
+            midstate.hash_with_suffix(black_box(&nonce.to_le_bytes()))
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, header_hashing);
+criterion_main!(benches);
@@ -0,0 +1,164 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BtcError, Result};
+
+/// A value in satoshis. All arithmetic is checked, consensus code must
+/// never wrap around or go past `MAX_MONEY`.
+#[derive(
+    Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    /// satoshis in one coin
+    pub const COIN: Amount = Amount(100_000_000);
+    /// no single value, or sum of values, may exceed this
+    pub const MAX_MONEY: Amount = Amount(21_000_000 * 100_000_000);
+
+    pub const fn from_sat(sat: u64) -> Self {
+        Amount(sat)
+    }
+
+    pub fn from_btc(btc: u64) -> Option<Self> {
+        btc.checked_mul(Self::COIN.0).map(Amount)
+    }
+
+    pub const fn to_sat(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_valid(self) -> bool {
+        self <= Self::MAX_MONEY
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0
+            .checked_add(other.0)
+            .map(Amount)
+            .filter(|amount| amount.is_valid())
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// `None` on overflow or if the total exceeds `MAX_MONEY`
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+}
+
+/// Whole coins with up to 8 decimals, e.g. `1.5 BTC`
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let coins = self.0 / Self::COIN.0;
+        let sats = self.0 % Self::COIN.0;
+        if sats == 0 {
+            write!(f, "{coins} BTC")
+        } else {
+            let fraction = format!("{sats:08}");
+            write!(f, "{coins}.{} BTC", fraction.trim_end_matches('0'))
+        }
+    }
+}
+
+/// Accepts `1.5`, `1.5 BTC` and `150000000 sat`
+impl FromStr for Amount {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        let amount = if let Some(sats) = s.strip_suffix("sats").or_else(|| s.strip_suffix("sat")) {
+            sats.trim()
+                .parse()
+                .map(Amount)
+                .map_err(|_| BtcError::InvalidAmount)?
+        } else {
+            let coins = s.strip_suffix("BTC").unwrap_or(s).trim();
+            let (whole, fraction) = coins.split_once('.').unwrap_or((coins, ""));
+            if whole.is_empty() && fraction.is_empty()
+                || fraction.len() > 8
+                || !whole.chars().all(|c| c.is_ascii_digit())
+                || !fraction.chars().all(|c| c.is_ascii_digit())
+            {
+                return Err(BtcError::InvalidAmount);
+            }
+
+            let whole: u64 = if whole.is_empty() {
+                0
+            } else {
+                whole.parse().map_err(|_| BtcError::InvalidAmount)?
+            };
+            let fraction: u64 = format!("{fraction:0<8}")
+                .parse()
+                .map_err(|_| BtcError::InvalidAmount)?;
+
+            Amount::from_btc(whole)
+                .and_then(|amount| amount.0.checked_add(fraction))
+                .map(Amount)
+                .ok_or(BtcError::InvalidAmount)?
+        };
+
+        if !amount.is_valid() {
+            return Err(BtcError::InvalidAmount);
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(Amount::from_sat(150_000_000).to_string(), "1.5 BTC");
+        assert_eq!(Amount::from_sat(1).to_string(), "0.00000001 BTC");
+        assert_eq!(Amount::from_btc(50).unwrap().to_string(), "50 BTC");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "1.5 BTC".parse::<Amount>().unwrap(),
+            Amount::from_sat(150_000_000)
+        );
+        assert_eq!(
+            "1.5".parse::<Amount>().unwrap(),
+            Amount::from_sat(150_000_000)
+        );
+        assert_eq!(
+            ".1".parse::<Amount>().unwrap(),
+            Amount::from_sat(10_000_000)
+        );
+        assert_eq!("42 sat".parse::<Amount>().unwrap(), Amount::from_sat(42));
+        assert!("0.000000001".parse::<Amount>().is_err());
+        assert!("-1".parse::<Amount>().is_err());
+        assert!("21000001".parse::<Amount>().is_err());
+        assert!(".".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(
+            Amount::checked_sum([Amount::COIN, Amount::COIN]),
+            Amount::from_btc(2)
+        );
+        assert_eq!(
+            Amount::checked_sum([Amount::MAX_MONEY, Amount::from_sat(1)]),
+            None
+        );
+        assert_eq!(
+            Amount::checked_sum([Amount::from_sat(u64::MAX), Amount::COIN]),
+            None
+        );
+        assert_eq!(Amount::ZERO.checked_sub(Amount::from_sat(1)), None);
+    }
+}
@@ -5,6 +5,7 @@ use chrono::Utc;
 use uuid::Uuid;
 
 use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
 use btclib::sha256::Hash;
 use btclib::types::{Block, BlockHeader, Transaction, TransactionOutput};
 use btclib::util::{MerkleRoot, Saveable};
@@ -13,22 +14,32 @@ fn main() {
     let path = if let Some(arg) = env::args().nth(1) {
         arg
     } else {
-        eprintln!("Usage: block_gen <block_file>");
+        eprintln!("Usage: block_gen <block_file> [network|params_file]");
         exit(1);
     };
+    let params = match env::args().nth(2) {
+        Some(arg) => ChainParams::from_arg(&arg).expect("failed load chain params"),
+        None => ChainParams::default(),
+    };
 
     let private_key = PrivateKey::new_key();
     let transactions = vec![Transaction::new(
         vec![],
         vec![TransactionOutput {
             unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
+            value: params.block_reward(0),
             pubkey: private_key.public_key(),
         }],
     )];
-    let merkle_root = MerkleRoot::calculate(&transactions);
+    let merkle_root = MerkleRoot::calculate(&transactions).expect("failed calculate merkle root");
     let block = Block::new(
-        BlockHeader::new(Utc::now(), 0, Hash::zero(), merkle_root, btclib::MIN_TARGET),
+        BlockHeader::new(
+            Utc::now(),
+            0,
+            Hash::zero(),
+            merkle_root,
+            params.max_target(),
+        ),
         transactions,
     );
     block.save_to_file(&path).expect("failed save block");
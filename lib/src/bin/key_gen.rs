@@ -4,9 +4,16 @@ use btclib::crypto::PrivateKey;
 use btclib::util::Saveable;
 
 fn main() {
-    let name = env::args().nth(1).expect("Please provide file name");
+    let name = env::args()
+        .nth(1)
+        .expect("Please provide file name (add --schnorr for an x-only key)");
+    let schnorr = env::args().nth(2).as_deref() == Some("--schnorr");
     let private_key = PrivateKey::new_key();
-    let pub_key = private_key.public_key();
+    let pub_key = if schnorr {
+        private_key.x_only_public_key()
+    } else {
+        private_key.public_key()
+    };
     let pub_key_file = name.clone() + ".pub.pem";
     let private_key_file = name + ".priv.cbor";
     private_key.save_to_file(&private_key_file).unwrap();
@@ -4,6 +4,7 @@ use std::process::exit;
 use uuid::Uuid;
 
 use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
 use btclib::types::{Transaction, TransactionOutput};
 use btclib::util::Saveable;
 
@@ -11,16 +12,20 @@ fn main() {
     let path = if let Some(arg) = env::args().nth(1) {
         arg
     } else {
-        eprintln!("Usage: tx_gen <tx_file>");
+        eprintln!("Usage: tx_gen <tx_file> [network|params_file]");
         exit(1);
     };
+    let params = match env::args().nth(2) {
+        Some(arg) => ChainParams::from_arg(&arg).expect("failed load chain params"),
+        None => ChainParams::default(),
+    };
 
     let private_key = PrivateKey::new_key();
     let transaction = Transaction::new(
         vec![],
         vec![TransactionOutput {
             unique_id: Uuid::new_v4(),
-            value: btclib::INITIAL_REWARD * 10u64.pow(8),
+            value: params.block_reward(0),
             pubkey: private_key.public_key(),
         }],
     );
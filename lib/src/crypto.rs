@@ -1,34 +1,294 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 use ecdsa::signature::Verifier;
 use ecdsa::{signature::Signer, Signature as ECDSASignature, SigningKey, VerifyingKey};
-use k256::Secp256k1;
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::DecompactPoint;
+use k256::elliptic_curve::{Field, PrimeField};
+use k256::{schnorr, AffinePoint, FieldBytes, ProjectivePoint, Scalar, Secp256k1};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use spki::EncodePublicKey;
 
+use crate::encode::Encodable;
 use crate::sha256::Hash;
 use crate::util::Saveable;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct Signature(pub ECDSASignature<Secp256k1>);
+pub enum Signature {
+    Ecdsa(ECDSASignature<Secp256k1>),
+    Schnorr(SchnorrSignature),
+}
+
 impl Signature {
     // sign trx output
     pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
         let signature = signing_key.sign(&output_hash.as_bytes());
-        Signature(signature)
+        Signature::Ecdsa(signature)
+    }
+
+    // sign trx output, BIP340
+    pub fn sign_output_schnorr(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+        let signing_key = private_key.schnorr_signing_key();
+        let aux_rand: [u8; 32] = rand::random();
+        let signature = signing_key
+            .sign_raw(&output_hash.as_bytes(), &aux_rand)
+            .expect("sign_output_schnorr: signing failed");
+        Signature::Schnorr(SchnorrSignature(signature))
+    }
+
+    /// Sign with the scheme the key locking the output expects
+    pub fn sign_output_for(
+        output_hash: &Hash,
+        private_key: &PrivateKey,
+        public_key: &PublicKey,
+    ) -> Self {
+        match public_key {
+            PublicKey::Ecdsa(_) => Self::sign_output(output_hash, private_key),
+            PublicKey::Schnorr(_) => Self::sign_output_schnorr(output_hash, private_key),
+        }
     }
-    // verify signature
+
+    // verify signature, the scheme of signature and key must match
     pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
-        public_key
-            .0
-            .verify(&output_hash.as_bytes(), &self.0)
-            .is_ok()
+        match (self, public_key) {
+            (Signature::Ecdsa(signature), PublicKey::Ecdsa(key)) => {
+                key.verify(&output_hash.as_bytes(), signature).is_ok()
+            }
+            (Signature::Schnorr(signature), PublicKey::Schnorr(key)) => key
+                .0
+                .verify_raw(&output_hash.as_bytes(), &signature.0)
+                .is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// BIP340 signature
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchnorrSignature(pub schnorr::Signature);
+
+// serialize as the 64 raw bytes
+impl Serialize for SchnorrSignature {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for SchnorrSignature {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes: Vec<u8> = Vec::<u8>::deserialize(deserializer)?;
+        schnorr::Signature::try_from(bytes.as_slice())
+            .map(SchnorrSignature)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// BIP340 x-only public key
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct XOnlyPublicKey(pub schnorr::VerifyingKey);
+
+impl XOnlyPublicKey {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        schnorr::VerifyingKey::from_bytes(bytes)
+            .ok()
+            .map(XOnlyPublicKey)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes().into()
+    }
+
+    /// Check many signatures at once: with random weights a_i,
+    /// (sum a_i s_i) G == sum a_i R_i + sum a_i e_i P_i holds for all
+    /// valid sets and fails for an invalid one with overwhelming probability.
+    /// Only says whether all are valid, not which one is not.
+    pub fn verify_batch(batch: &[(&Hash, &SchnorrSignature, &XOnlyPublicKey)]) -> bool {
+        let mut rng = rand::thread_rng();
+        let mut terms = Vec::with_capacity(2 * batch.len() + 1);
+        let mut s_sum = Scalar::ZERO;
+
+        for (i, (output_hash, signature, public_key)) in batch.iter().enumerate() {
+            let bytes = signature.0.to_bytes();
+            let (r_bytes, s_bytes) = bytes.split_at(32);
+
+            let r_point: Option<AffinePoint> =
+                AffinePoint::decompact(FieldBytes::from_slice(r_bytes)).into();
+            let s: Option<Scalar> = Scalar::from_repr(*FieldBytes::from_slice(s_bytes)).into();
+            let (r_point, s) = match (r_point, s) {
+                (Some(r_point), Some(s)) => (r_point, s),
+                _ => return false,
+            };
+
+            let e = <Scalar as Reduce<k256::U256>>::reduce_bytes(
+                &tagged_hash(b"BIP0340/challenge")
+                    .chain_update(r_bytes)
+                    .chain_update(public_key.to_bytes())
+                    .chain_update(output_hash.as_bytes())
+                    .finalize(),
+            );
+            let a = if i == 0 {
+                Scalar::ONE
+            } else {
+                Scalar::random(&mut rng)
+            };
+
+            s_sum += a * s;
+            terms.push((ProjectivePoint::from(r_point), a));
+            terms.push((ProjectivePoint::from(*public_key.0.as_affine()), a * e));
+        }
+        terms.push((ProjectivePoint::GENERATOR, -s_sum));
+
+        multi_scalar_mul(&terms) == ProjectivePoint::IDENTITY
+    }
+}
+
+fn tagged_hash(tag: &[u8]) -> Sha256 {
+    let tag_hash = Sha256::digest(tag);
+    let mut digest = Sha256::new();
+    digest.update(tag_hash);
+    digest.update(tag_hash);
+    digest
+}
+
+// sum of point * scalar, sharing the doublings between all terms (Straus, 4 bit windows)
+fn multi_scalar_mul(terms: &[(ProjectivePoint, Scalar)]) -> ProjectivePoint {
+    let tables: Vec<[ProjectivePoint; 16]> = terms
+        .iter()
+        .map(|(point, _)| {
+            let mut table = [ProjectivePoint::IDENTITY; 16];
+            for i in 1..16 {
+                table[i] = table[i - 1] + point;
+            }
+            table
+        })
+        .collect();
+    let scalars: Vec<FieldBytes> = terms.iter().map(|(_, scalar)| scalar.to_bytes()).collect();
+
+    let mut acc = ProjectivePoint::IDENTITY;
+    for byte in 0..32 {
+        for shift in [4, 0] {
+            for _ in 0..4 {
+                acc = acc.double();
+            }
+            for (table, scalar) in tables.iter().zip(&scalars) {
+                let nibble = (scalar[byte] >> shift) & 0x0f;
+                if nibble != 0 {
+                    acc += table[nibble as usize];
+                }
+            }
+        }
+    }
+    acc
+}
+
+/// Remembers signatures that already verified, so a transaction checked on
+/// mempool entry is not checked again when its block arrives
+#[derive(Debug, Clone)]
+pub struct SignatureCache {
+    // by the sequence number each entry was inserted under
+    entries: HashMap<Hash, u64>,
+    // insertion order, oldest first; an entry removed or inserted again
+    // since leaves a stale item behind, skipped on eviction
+    order: VecDeque<(Hash, u64)>,
+    next: u64,
+    capacity: usize,
+}
+
+impl Default for SignatureCache {
+    fn default() -> Self {
+        SignatureCache::with_capacity(Self::MAX_ENTRIES)
+    }
+}
+
+impl SignatureCache {
+    // bounds memory; once full, each new entry evicts the oldest one
+    const MAX_ENTRIES: usize = 100_000;
+
+    pub fn new() -> Self {
+        SignatureCache::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        SignatureCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next: 0,
+            capacity,
+        }
+    }
+
+    fn key(output_hash: &Hash, signature: &Signature, public_key: &PublicKey) -> Hash {
+        let mut bytes = output_hash.consensus_bytes();
+        signature.consensus_encode(&mut bytes).unwrap();
+        public_key.consensus_encode(&mut bytes).unwrap();
+        Hash::hash_bytes(&bytes)
+    }
+
+    pub fn contains(
+        &self,
+        output_hash: &Hash,
+        signature: &Signature,
+        public_key: &PublicKey,
+    ) -> bool {
+        self.entries
+            .contains_key(&Self::key(output_hash, signature, public_key))
+    }
+
+    pub fn insert(&mut self, output_hash: &Hash, signature: &Signature, public_key: &PublicKey) {
+        let key = Self::key(output_hash, signature, public_key);
+        if self.entries.contains_key(&key) {
+            return;
+        }
+        self.entries.insert(key, self.next);
+        self.order.push_back((key, self.next));
+        self.next += 1;
+        while self.entries.len() > self.capacity {
+            let Some((oldest, sequence)) = self.order.pop_front() else {
+                break;
+            };
+            if self.entries.get(&oldest) == Some(&sequence) {
+                self.entries.remove(&oldest);
+            }
+        }
+        // removals alone never evict, so drop their stale items now and then
+        if self.order.len() > 2 * self.capacity {
+            let entries = &self.entries;
+            self.order
+                .retain(|(key, sequence)| entries.get(key) == Some(sequence));
+        }
+    }
+
+    pub fn remove(&mut self, output_hash: &Hash, signature: &Signature, public_key: &PublicKey) {
+        self.entries
+            .remove(&Self::key(output_hash, signature, public_key));
+    }
+
+    /// Verify through the cache, remembering the signature if it is valid
+    pub fn verify(
+        &mut self,
+        output_hash: &Hash,
+        signature: &Signature,
+        public_key: &PublicKey,
+    ) -> bool {
+        if self.contains(output_hash, signature, public_key) {
+            return true;
+        }
+        let valid = signature.verify(output_hash, public_key);
+        if valid {
+            self.insert(output_hash, signature, public_key);
+        }
+        valid
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
-pub struct PublicKey(pub VerifyingKey<Secp256k1>);
+pub enum PublicKey {
+    Ecdsa(VerifyingKey<Secp256k1>),
+    Schnorr(XOnlyPublicKey),
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PrivateKey(#[serde(with = "signkey_serde")] pub SigningKey<Secp256k1>);
@@ -39,7 +299,16 @@ impl PrivateKey {
     }
 
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey::Ecdsa(*self.0.verifying_key())
+    }
+
+    /// Key for outputs that are to be spent with a Schnorr signature
+    pub fn x_only_public_key(&self) -> PublicKey {
+        PublicKey::Schnorr(XOnlyPublicKey(*self.schnorr_signing_key().verifying_key()))
+    }
+
+    fn schnorr_signing_key(&self) -> schnorr::SigningKey {
+        schnorr::SigningKey::from(*self.0.as_nonzero_scalar())
     }
 }
 
@@ -78,24 +347,160 @@ impl Saveable for PrivateKey {
     }
 }
 
-// save and load as PEM file
+// save and load ECDSA keys as PEM file, x-only keys as hex
 impl Saveable for PublicKey {
     fn load<I: Read>(mut reader: I) -> IoResult<Self> {
         let mut buf = String::new();
         reader.read_to_string(&mut buf)?;
 
+        if !buf.trim_start().starts_with("-----BEGIN") {
+            return hex::decode(buf.trim())
+                .ok()
+                .and_then(|bytes| XOnlyPublicKey::from_bytes(&bytes))
+                .map(PublicKey::Schnorr)
+                .ok_or_else(|| {
+                    IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey")
+                });
+        }
+
         let public_key = buf
             .parse()
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to parse PublicKey"))?;
-        Ok(PublicKey(public_key))
+        Ok(PublicKey::Ecdsa(public_key))
     }
 
     fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
-        let s = self
-            .0
-            .to_public_key_pem(Default::default())
-            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize PublicKey"))?;
+        let s = match self {
+            PublicKey::Ecdsa(key) => key.to_public_key_pem(Default::default()).map_err(|_| {
+                IoError::new(IoErrorKind::InvalidData, "Failed to serialize PublicKey")
+            })?,
+            PublicKey::Schnorr(key) => hex::encode(key.to_bytes()) + "\n",
+        };
         writer.write_all(s.as_bytes())?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schnorr_sign_and_verify() {
+        let private_key = PrivateKey::new_key();
+        let output_hash = Hash::hash(&"output");
+        let signature = Signature::sign_output_schnorr(&output_hash, &private_key);
+
+        assert!(signature.verify(&output_hash, &private_key.x_only_public_key()));
+        assert!(!signature.verify(&Hash::hash(&"other"), &private_key.x_only_public_key()));
+        // the scheme is chosen by the key
+        assert!(!signature.verify(&output_hash, &private_key.public_key()));
+    }
+
+    #[test]
+    fn signature_cache_hits_and_evicts() {
+        let key = PrivateKey::new_key();
+        let hashes: Vec<Hash> = (0..3).map(|i| Hash::hash(&i)).collect();
+        let signatures: Vec<Signature> = hashes
+            .iter()
+            .map(|hash| Signature::sign_output(hash, &key))
+            .collect();
+        let public_key = key.public_key();
+        let mut cache = SignatureCache::with_capacity(2);
+
+        // a miss verifies and caches, a bad signature is never cached
+        assert!(!cache.contains(&hashes[0], &signatures[0], &public_key));
+        assert!(cache.verify(&hashes[0], &signatures[0], &public_key));
+        assert!(cache.contains(&hashes[0], &signatures[0], &public_key));
+        assert!(!cache.verify(&hashes[1], &signatures[0], &public_key));
+        assert!(!cache.contains(&hashes[1], &signatures[0], &public_key));
+
+        // full: only the oldest entry goes
+        assert!(cache.verify(&hashes[1], &signatures[1], &public_key));
+        assert!(cache.verify(&hashes[2], &signatures[2], &public_key));
+        assert!(!cache.contains(&hashes[0], &signatures[0], &public_key));
+        assert!(cache.contains(&hashes[1], &signatures[1], &public_key));
+        assert!(cache.contains(&hashes[2], &signatures[2], &public_key));
+    }
+
+    #[test]
+    fn signature_cache_evicts_reinserted_entries_in_order() {
+        let key = PrivateKey::new_key();
+        let hashes: Vec<Hash> = (0..3).map(|i| Hash::hash(&i)).collect();
+        let signatures: Vec<Signature> = hashes
+            .iter()
+            .map(|hash| Signature::sign_output(hash, &key))
+            .collect();
+        let public_key = key.public_key();
+        let mut cache = SignatureCache::with_capacity(2);
+
+        cache.insert(&hashes[0], &signatures[0], &public_key);
+        cache.insert(&hashes[1], &signatures[1], &public_key);
+        cache.remove(&hashes[0], &signatures[0], &public_key);
+        assert!(!cache.contains(&hashes[0], &signatures[0], &public_key));
+        // now newer than 1, so 1 goes first
+        cache.insert(&hashes[0], &signatures[0], &public_key);
+        cache.insert(&hashes[2], &signatures[2], &public_key);
+        assert!(cache.contains(&hashes[0], &signatures[0], &public_key));
+        assert!(!cache.contains(&hashes[1], &signatures[1], &public_key));
+        assert!(cache.contains(&hashes[2], &signatures[2], &public_key));
+
+        // removals do not pile up
+        for _ in 0..10 {
+            cache.remove(&hashes[0], &signatures[0], &public_key);
+            cache.insert(&hashes[0], &signatures[0], &public_key);
+        }
+        assert!(cache.order.len() <= 2 * cache.capacity);
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn batch_verification() {
+        let keys: Vec<PrivateKey> = (0..8).map(|_| PrivateKey::new_key()).collect();
+        let hashes: Vec<Hash> = (0..8).map(|i| Hash::hash(&i)).collect();
+        let public_keys: Vec<XOnlyPublicKey> = keys
+            .iter()
+            .map(|key| match key.x_only_public_key() {
+                PublicKey::Schnorr(key) => key,
+                PublicKey::Ecdsa(_) => unreachable!(),
+            })
+            .collect();
+        let mut signatures: Vec<SchnorrSignature> = keys
+            .iter()
+            .zip(&hashes)
+            .map(
+                |(key, hash)| match Signature::sign_output_schnorr(hash, key) {
+                    Signature::Schnorr(signature) => signature,
+                    Signature::Ecdsa(_) => unreachable!(),
+                },
+            )
+            .collect();
+
+        let batch = |signatures: &[SchnorrSignature]| {
+            let batch: Vec<_> = hashes
+                .iter()
+                .zip(signatures)
+                .zip(&public_keys)
+                .map(|((hash, signature), key)| (hash, signature, key))
+                .collect();
+            XOnlyPublicKey::verify_batch(&batch)
+        };
+        assert!(batch(&signatures));
+
+        signatures.swap(2, 5);
+        assert!(!batch(&signatures));
+    }
+
+    #[test]
+    fn x_only_key_save_load() {
+        let public_key = PrivateKey::new_key().x_only_public_key();
+        let mut buf = vec![];
+        public_key.save(&mut buf).unwrap();
+        assert_eq!(PublicKey::load(buf.as_slice()).unwrap(), public_key);
+
+        let public_key = PrivateKey::new_key().public_key();
+        let mut buf = vec![];
+        public_key.save(&mut buf).unwrap();
+        assert_eq!(PublicKey::load(buf.as_slice()).unwrap(), public_key);
+    }
+}
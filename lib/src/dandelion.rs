@@ -0,0 +1,156 @@
+//! Dandelion-style transaction relay.
+//!
+//! A new transaction first travels a stem: each node forwards it to a single
+//! peer with `StemTransaction`, until one node randomly switches to the fluff
+//! phase and broadcasts it normally. Nodes on the stem start an embargo timer
+//! and fluff the transaction themselves if it is not seen broadcast in time,
+//! so a stem that drops it cannot stop it from propagating.
+//!
+//! Until then a stemmed transaction lives only in this stempool, never in the
+//! mempool, so nothing that serves the mempool can give it away.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::sha256::Hash;
+use crate::types::Transaction;
+
+/// Chance that a stem node switches to fluff
+pub const FLUFF_PROBABILITY: f64 = 0.1;
+/// How long a node keeps the same stem successor
+pub const EPOCH: Duration = Duration::from_secs(10 * 60);
+/// Fail-safe timer, plus a random extra of up to `EMBARGO_JITTER`
+pub const EMBARGO: Duration = Duration::from_secs(30);
+pub const EMBARGO_JITTER: Duration = Duration::from_secs(15);
+
+/// Where a transaction goes next
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// Forward with `StemTransaction` to this peer only
+    Stem(String),
+    /// Broadcast with `NewTransaction`/`Inv`
+    Fluff,
+}
+
+#[derive(Clone, Debug)]
+pub struct Dandelion {
+    pub fluff_probability: f64,
+    // stem successor and when it was picked
+    successor: Option<(String, Instant)>,
+    // the stempool: stemmed transactions and when to fluff them ourselves
+    embargoes: HashMap<Hash, (Transaction, Instant)>,
+}
+
+impl Default for Dandelion {
+    fn default() -> Self {
+        Dandelion {
+            fluff_probability: FLUFF_PROBABILITY,
+            successor: None,
+            embargoes: HashMap::new(),
+        }
+    }
+}
+
+impl Dandelion {
+    pub fn new() -> Self {
+        Dandelion::default()
+    }
+
+    /// Route a transaction submitted by a wallet or received as a stem.
+    /// `peers` are the outbound peers that may be a stem successor.
+    pub fn route(&mut self, transaction: &Transaction, peers: &[String], now: Instant) -> Route {
+        let mut rng = rand::thread_rng();
+        if peers.is_empty() || rng.gen_bool(self.fluff_probability) {
+            return Route::Fluff;
+        }
+
+        let successor = match &self.successor {
+            Some((peer, picked)) if now.duration_since(*picked) < EPOCH && peers.contains(peer) => {
+                peer.clone()
+            }
+            _ => {
+                let peer = peers.choose(&mut rng).expect("peers is not empty").clone();
+                self.successor = Some((peer.clone(), now));
+                peer
+            }
+        };
+
+        let deadline = now + EMBARGO + EMBARGO_JITTER.mul_f64(rng.gen());
+        self.embargoes
+            .entry(transaction.hash())
+            .or_insert_with(|| (transaction.clone(), deadline));
+        Route::Stem(successor)
+    }
+
+    /// A transaction we passed along a stem and have not seen fluffed yet
+    pub fn stemmed(&self, transaction: &Hash) -> Option<&Transaction> {
+        self.embargoes
+            .get(transaction)
+            .map(|(transaction, _)| transaction)
+    }
+
+    /// The transaction was seen in the fluff phase, its embargo is over
+    pub fn fluffed(&mut self, transaction: &Hash) {
+        self.embargoes.remove(transaction);
+    }
+
+    /// Stemmed transactions whose embargo ran out, to be fluffed now
+    pub fn expired(&mut self, now: Instant) -> Vec<Transaction> {
+        let expired: Vec<Hash> = self
+            .embargoes
+            .iter()
+            .filter(|(_, (_, deadline))| *deadline <= now)
+            .map(|(hash, _)| *hash)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|hash| self.embargoes.remove(&hash))
+            .map(|(transaction, _)| transaction)
+            .collect()
+    }
+
+    /// Forget the stem successor, e.g. when it disconnects
+    pub fn reset_successor(&mut self) {
+        self.successor = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stems_then_fails_safe() {
+        let transaction = Transaction::new(vec![], vec![]);
+        let peers = vec!["10.0.0.1:9000".to_string(), "10.0.0.2:9000".to_string()];
+        let mut dandelion = Dandelion {
+            fluff_probability: 0.0,
+            ..Dandelion::new()
+        };
+
+        let now = Instant::now();
+        let Route::Stem(successor) = dandelion.route(&transaction, &peers, now) else {
+            panic!("expected a stem");
+        };
+        // same successor for the whole epoch
+        assert_eq!(
+            dandelion.route(&transaction, &peers, now),
+            Route::Stem(successor)
+        );
+        assert_eq!(dandelion.route(&transaction, &[], now), Route::Fluff);
+
+        assert!(dandelion.expired(now).is_empty());
+        let expired = dandelion.expired(now + EMBARGO + EMBARGO_JITTER);
+        assert_eq!(expired.len(), 1);
+        assert!(dandelion.expired(now + EMBARGO * 10).is_empty());
+
+        dandelion.route(&transaction, &peers, now);
+        assert!(dandelion.stemmed(&transaction.hash()).is_some());
+        dandelion.fluffed(&transaction.hash());
+        assert!(dandelion.stemmed(&transaction.hash()).is_none());
+        assert!(dandelion.expired(now + EMBARGO * 10).is_empty());
+    }
+}
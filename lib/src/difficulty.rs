@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::BlockHeader;
+use crate::U256;
+
+/// Computes the target the block at a given height has to meet
+pub trait DifficultyAlgorithm {
+    /// `height` is the height of the block being mined, `header_at` returns
+    /// the already accepted header at any lower height.
+    /// The result is not clamped to the network's easiest target, callers do that.
+    fn next_target<'a>(&self, height: u64, header_at: &dyn Fn(u64) -> &'a BlockHeader) -> U256;
+
+    /// Reject parameters `next_target` cannot work with, such as zero divisors
+    fn validate(&self) -> Result<(), String>;
+}
+
+/// Selects the difficulty algorithm of a network
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub enum DifficultyAdjustment {
+    /// Never retarget, for test networks
+    Fixed {
+        /// seconds
+        block_time: u64,
+    },
+    Windowed(Windowed),
+    Lwma(Lwma),
+    Asert(Asert),
+}
+
+impl Default for DifficultyAdjustment {
+    fn default() -> Self {
+        DifficultyAdjustment::Windowed(Windowed::default())
+    }
+}
+
+impl DifficultyAdjustment {
+    /// Block interval the algorithm aims for, in seconds
+    pub fn block_time(&self) -> u64 {
+        match self {
+            DifficultyAdjustment::Fixed { block_time } => *block_time,
+            DifficultyAdjustment::Windowed(algorithm) => algorithm.block_time,
+            DifficultyAdjustment::Lwma(algorithm) => algorithm.block_time,
+            DifficultyAdjustment::Asert(algorithm) => algorithm.block_time,
+        }
+    }
+}
+
+impl DifficultyAlgorithm for DifficultyAdjustment {
+    fn next_target<'a>(&self, height: u64, header_at: &dyn Fn(u64) -> &'a BlockHeader) -> U256 {
+        match self {
+            DifficultyAdjustment::Fixed { .. } => header_at(height - 1).target(),
+            DifficultyAdjustment::Windowed(algorithm) => algorithm.next_target(height, header_at),
+            DifficultyAdjustment::Lwma(algorithm) => algorithm.next_target(height, header_at),
+            DifficultyAdjustment::Asert(algorithm) => algorithm.next_target(height, header_at),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            DifficultyAdjustment::Fixed { block_time } => non_zero("block_time", *block_time),
+            DifficultyAdjustment::Windowed(algorithm) => algorithm.validate(),
+            DifficultyAdjustment::Lwma(algorithm) => algorithm.validate(),
+            DifficultyAdjustment::Asert(algorithm) => algorithm.validate(),
+        }
+    }
+}
+
+/// The original algorithm: every `interval` blocks scale the target by
+/// actual / expected time of the last window, by at most `max_factor`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Windowed {
+    /// blocks between adjustments
+    pub interval: u64,
+    /// seconds
+    pub block_time: u64,
+    pub max_factor: u64,
+}
+
+impl Default for Windowed {
+    fn default() -> Self {
+        Windowed {
+            interval: 50,
+            block_time: 10,
+            max_factor: 4,
+        }
+    }
+}
+
+impl DifficultyAlgorithm for Windowed {
+    fn next_target<'a>(&self, height: u64, header_at: &dyn Fn(u64) -> &'a BlockHeader) -> U256 {
+        let current_target = header_at(height - 1).target();
+        if !height.is_multiple_of(self.interval) {
+            return current_target;
+        }
+
+        let start_time = header_at(height - self.interval).timestamp;
+        let end_time = header_at(height - 1).timestamp;
+        let actual_seconds = (end_time - start_time).num_seconds().max(0) as u64;
+        let target_seconds = self.block_time * self.interval;
+
+        let new_target = mul_div(current_target, actual_seconds, target_seconds);
+
+        let min = current_target / self.max_factor;
+        let max = current_target.saturating_mul(U256::from(self.max_factor));
+        new_target.clamp(min, max)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        non_zero("interval", self.interval)?;
+        non_zero("block_time", self.block_time)?;
+        non_zero("max_factor", self.max_factor)
+    }
+}
+
+/// Linearly weighted moving average over the last `window` solve times (LWMA-1).
+/// Recent blocks weigh more, so the target follows hashrate changes within a few blocks.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lwma {
+    /// blocks averaged
+    pub window: u64,
+    /// seconds
+    pub block_time: u64,
+}
+
+impl Default for Lwma {
+    fn default() -> Self {
+        Lwma {
+            window: 45,
+            block_time: 10,
+        }
+    }
+}
+
+impl DifficultyAlgorithm for Lwma {
+    fn next_target<'a>(&self, height: u64, header_at: &dyn Fn(u64) -> &'a BlockHeader) -> U256 {
+        // need window + 1 headers to get window solve times
+        if height <= self.window {
+            return header_at(height - 1).target();
+        }
+
+        let mut weighted_solve_times = 0u64;
+        let mut target_sum = U256::zero();
+        for i in 1..=self.window {
+            let block_height = height - self.window - 1 + i;
+            let header = header_at(block_height);
+            let previous = header_at(block_height - 1);
+
+            // timestamps are only bound by median time past, so clamp out
+            // negative and extreme solve times
+            let solve_time = (header.timestamp - previous.timestamp)
+                .num_seconds()
+                .clamp(1, 6 * self.block_time as i64) as u64;
+            weighted_solve_times += i * solve_time;
+            target_sum = target_sum.saturating_add(header.target());
+        }
+
+        // sum of weights is window * (window + 1) / 2
+        let k = self.window * (self.window + 1) / 2 * self.block_time;
+        let weighted_solve_times = weighted_solve_times.max(k / 3);
+        let average_target = target_sum / self.window;
+
+        mul_div(average_target, weighted_solve_times, k)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        non_zero("window", self.window)?;
+        non_zero("block_time", self.block_time)
+    }
+}
+
+/// Absolutely scheduled exponentially rising targets (aserti3-2d).
+/// The target doubles or halves for every `half_life` seconds the chain is
+/// behind or ahead of schedule, measured from the genesis block.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Asert {
+    /// seconds
+    pub half_life: u64,
+    /// seconds
+    pub block_time: u64,
+}
+
+impl Default for Asert {
+    fn default() -> Self {
+        Asert {
+            half_life: 60 * 60,
+            block_time: 10,
+        }
+    }
+}
+
+impl DifficultyAlgorithm for Asert {
+    fn next_target<'a>(&self, height: u64, header_at: &dyn Fn(u64) -> &'a BlockHeader) -> U256 {
+        let anchor = header_at(0);
+        let tip = header_at(height - 1);
+
+        let time_delta = (tip.timestamp - anchor.timestamp).num_seconds() as i128;
+        let height_delta = (height - 1) as i128;
+        // 16.16 fixed point exponent
+        let exponent = ((time_delta - self.block_time as i128 * height_delta) * 65536)
+            / self.half_life as i128;
+
+        let shifts = exponent >> 16;
+        let frac = (exponent & 0xffff) as u128;
+        // cubic approximation of 2^frac - 1, scaled by 2^16
+        let factor = 65536
+            + ((195_766_423_245_049 * frac
+                + 971_821_376 * frac * frac
+                + 5127 * frac * frac * frac
+                + (1 << 47))
+                >> 48);
+
+        let target = anchor.target().saturating_mul(U256::from(factor as u64));
+        let shifts = shifts - 16;
+        if shifts < 0 {
+            target >> (-shifts).min(256) as usize
+        } else if shifts >= 256 || target.leading_zeros() < shifts as u32 {
+            U256::MAX
+        } else {
+            target << shifts as usize
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        non_zero("half_life", self.half_life)?;
+        non_zero("block_time", self.block_time)
+    }
+}
+
+fn non_zero(name: &str, value: u64) -> Result<(), String> {
+    match value {
+        0 => Err(format!("{name} must not be zero")),
+        _ => Ok(()),
+    }
+}
+
+// target * numerator / denominator without overflowing for any target we use
+fn mul_div(target: U256, numerator: u64, denominator: u64) -> U256 {
+    match target.checked_mul(U256::from(numerator)) {
+        Some(product) => product / denominator,
+        None => (target / denominator).saturating_mul(U256::from(numerator)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, Utc};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::crypto::PrivateKey;
+    use crate::sha256::Hash;
+    use crate::types::{Transaction, TransactionOutput};
+    use crate::util::MerkleRoot;
+
+    fn headers(solve_times: &[i64], target: U256) -> Vec<BlockHeader> {
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: Amount::ZERO,
+                unique_id: Uuid::new_v4(),
+                pubkey: PrivateKey::new_key().public_key(),
+            }],
+        )];
+        let merkle_root = MerkleRoot::calculate(&transactions).unwrap();
+        let start = DateTime::<Utc>::from_timestamp(1_700_000_000, 0).unwrap();
+
+        let mut timestamp = start;
+        let mut headers = vec![BlockHeader::new(
+            timestamp,
+            0,
+            Hash::zero(),
+            merkle_root,
+            target,
+        )];
+        for solve_time in solve_times {
+            timestamp += Duration::seconds(*solve_time);
+            headers.push(BlockHeader::new(
+                timestamp,
+                0,
+                Hash::zero(),
+                merkle_root,
+                target,
+            ));
+        }
+        headers
+    }
+
+    fn next_target(algorithm: &impl DifficultyAlgorithm, headers: &[BlockHeader]) -> U256 {
+        algorithm.next_target(headers.len() as u64, &|height| &headers[height as usize])
+    }
+
+    #[test]
+    fn windowed_only_adjusts_on_interval() {
+        let target = U256::from(1u64) << 200;
+        let windowed = Windowed {
+            interval: 10,
+            block_time: 10,
+            max_factor: 4,
+        };
+
+        let chain = headers(&[5; 8], target);
+        assert_eq!(next_target(&windowed, &chain), target);
+
+        // 9 solve times of 5s over an expected 100s window
+        let chain = headers(&[5; 9], target);
+        assert_eq!(next_target(&windowed, &chain), mul_div(target, 45, 100));
+
+        let chain = headers(&[1000; 9], target);
+        assert_eq!(next_target(&windowed, &chain), target * 4);
+    }
+
+    #[test]
+    fn lwma_is_stable_on_schedule() {
+        let target = U256::from(1u64) << 200;
+        let lwma = Lwma {
+            window: 10,
+            block_time: 10,
+        };
+
+        let chain = headers(&[10; 20], target);
+        assert_eq!(next_target(&lwma, &chain), target);
+
+        // blocks twice as fast halve the target
+        let chain = headers(&[5; 20], target);
+        assert_eq!(next_target(&lwma, &chain), target / 2);
+    }
+
+    #[test]
+    fn asert_doubles_per_half_life_behind_schedule() {
+        let target = U256::from(1u64) << 200;
+        let asert = Asert {
+            half_life: 100,
+            block_time: 10,
+        };
+
+        let chain = headers(&[10; 20], target);
+        assert_eq!(next_target(&asert, &chain), target);
+
+        // 20 blocks, 100s behind schedule
+        let chain = headers(&[15; 20], target);
+        assert_eq!(next_target(&asert, &chain), target * 2);
+
+        let chain = headers(&[5; 20], target);
+        assert_eq!(next_target(&asert, &chain), target / 2);
+    }
+
+    #[test]
+    fn rejects_zero_parameters() {
+        assert!(DifficultyAdjustment::default().validate().is_ok());
+        let zeroed = [
+            DifficultyAdjustment::Fixed { block_time: 0 },
+            DifficultyAdjustment::Windowed(Windowed {
+                max_factor: 0,
+                ..Windowed::default()
+            }),
+            DifficultyAdjustment::Windowed(Windowed {
+                block_time: 0,
+                ..Windowed::default()
+            }),
+            DifficultyAdjustment::Windowed(Windowed {
+                interval: 0,
+                ..Windowed::default()
+            }),
+            DifficultyAdjustment::Lwma(Lwma {
+                window: 0,
+                ..Lwma::default()
+            }),
+            DifficultyAdjustment::Lwma(Lwma {
+                block_time: 0,
+                ..Lwma::default()
+            }),
+            DifficultyAdjustment::Asert(Asert {
+                half_life: 0,
+                ..Asert::default()
+            }),
+        ];
+        for difficulty in zeroed {
+            assert!(difficulty.validate().is_err(), "{difficulty:?}");
+        }
+    }
+}
@@ -0,0 +1,477 @@
+//! Consensus serialization.
+//!
+//! Everything that is hashed, signed or counted against a size limit goes
+//! through this encoding, never through serde: little-endian fixed-width
+//! integers, Bitcoin's CompactSize varints for lengths, and a leading
+//! version byte on headers and transactions. CBOR is only used for storage
+//! and the wire protocol.
+
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+use chrono::{DateTime, Utc};
+use ecdsa::{Signature as ECDSASignature, VerifyingKey};
+use k256::schnorr;
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::crypto::{PublicKey, SchnorrSignature, Signature, XOnlyPublicKey};
+use crate::sha256::Hash;
+use crate::target::CompactTarget;
+use crate::types::{Block, BlockHeader, Transaction, TransactionInput, TransactionOutput};
+use crate::util::MerkleRoot;
+
+/// Version byte written in front of every header and transaction
+pub const CONSENSUS_VERSION: u8 = 1;
+
+// keeps a malicious length prefix from allocating gigabytes
+const MAX_VEC_PREALLOCATION: u64 = 1024;
+
+const ECDSA_TAG: u8 = 0;
+const SCHNORR_TAG: u8 = 1;
+
+pub trait Encodable {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()>;
+
+    fn consensus_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.consensus_encode(&mut bytes)
+            .expect("consensus_bytes: writing to a Vec never fails");
+        bytes
+    }
+
+    /// Size in bytes, used for block and transaction size limits
+    fn consensus_size(&self) -> usize {
+        self.consensus_bytes().len()
+    }
+}
+
+pub trait Decodable: Sized {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self>;
+
+    /// Decode and require that all bytes are consumed
+    fn from_consensus_bytes(mut bytes: &[u8]) -> IoResult<Self> {
+        let value = Self::consensus_decode(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(invalid_data("trailing bytes"));
+        }
+        Ok(value)
+    }
+}
+
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_string())
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> IoResult<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Bitcoin's CompactSize
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VarInt(pub u64);
+
+impl Encodable for VarInt {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self.0 {
+            0..=0xfc => writer.write_all(&[self.0 as u8]),
+            0xfd..=0xffff => {
+                writer.write_all(&[0xfd])?;
+                writer.write_all(&(self.0 as u16).to_le_bytes())
+            }
+            0x1_0000..=0xffff_ffff => {
+                writer.write_all(&[0xfe])?;
+                writer.write_all(&(self.0 as u32).to_le_bytes())
+            }
+            _ => {
+                writer.write_all(&[0xff])?;
+                writer.write_all(&self.0.to_le_bytes())
+            }
+        }
+    }
+}
+
+impl Decodable for VarInt {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let [prefix] = read_array(reader)?;
+        // only the shortest encoding is canonical
+        let (value, min) = match prefix {
+            0xfd => (u16::from_le_bytes(read_array(reader)?) as u64, 0xfd),
+            0xfe => (u32::from_le_bytes(read_array(reader)?) as u64, 0x1_0000),
+            0xff => (u64::from_le_bytes(read_array(reader)?), 0x1_0000_0000),
+            _ => return Ok(VarInt(prefix as u64)),
+        };
+        if value < min {
+            return Err(invalid_data("non-canonical varint"));
+        }
+        Ok(VarInt(value))
+    }
+}
+
+impl Encodable for u8 {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&[*self])
+    }
+}
+
+impl Decodable for u8 {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let [byte] = read_array(reader)?;
+        Ok(byte)
+    }
+}
+
+impl Encodable for u32 {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl Decodable for u32 {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(u32::from_le_bytes(read_array(reader)?))
+    }
+}
+
+impl Encodable for u64 {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.to_le_bytes())
+    }
+}
+
+impl Decodable for u64 {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(u64::from_le_bytes(read_array(reader)?))
+    }
+}
+
+impl<T: Encodable> Encodable for Vec<T> {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        VarInt(self.len() as u64).consensus_encode(writer)?;
+        for item in self {
+            item.consensus_encode(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Decodable> Decodable for Vec<T> {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let VarInt(len) = VarInt::consensus_decode(reader)?;
+        let mut items = Vec::with_capacity(len.min(MAX_VEC_PREALLOCATION) as usize);
+        for _ in 0..len {
+            items.push(T::consensus_decode(reader)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encodable for Hash {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.as_bytes())
+    }
+}
+
+impl Decodable for Hash {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Hash::from_bytes(read_array(reader)?))
+    }
+}
+
+impl Encodable for MerkleRoot {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.consensus_encode(writer)
+    }
+}
+
+impl Decodable for MerkleRoot {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(MerkleRoot(Hash::consensus_decode(reader)?))
+    }
+}
+
+impl Encodable for Amount {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.to_sat().consensus_encode(writer)
+    }
+}
+
+impl Decodable for Amount {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Amount::from_sat(u64::consensus_decode(reader)?))
+    }
+}
+
+/// seconds since the epoch (i64) and nanoseconds (u32)
+impl Encodable for DateTime<Utc> {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(&self.timestamp().to_le_bytes())?;
+        self.timestamp_subsec_nanos().consensus_encode(writer)
+    }
+}
+
+impl Decodable for DateTime<Utc> {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let seconds = i64::from_le_bytes(read_array(reader)?);
+        let nanos = u32::consensus_decode(reader)?;
+        DateTime::from_timestamp(seconds, nanos).ok_or_else(|| invalid_data("invalid timestamp"))
+    }
+}
+
+impl Encodable for Uuid {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        writer.write_all(self.as_bytes())
+    }
+}
+
+impl Decodable for Uuid {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Uuid::from_bytes(read_array(reader)?))
+    }
+}
+
+/// tag byte, then 33 byte compressed SEC1 (ECDSA) or 32 byte x-only (Schnorr)
+impl Encodable for PublicKey {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self {
+            PublicKey::Ecdsa(key) => {
+                writer.write_all(&[ECDSA_TAG])?;
+                writer.write_all(key.to_encoded_point(true).as_bytes())
+            }
+            PublicKey::Schnorr(key) => {
+                writer.write_all(&[SCHNORR_TAG])?;
+                writer.write_all(&key.to_bytes())
+            }
+        }
+    }
+}
+
+impl Decodable for PublicKey {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        match u8::consensus_decode(reader)? {
+            ECDSA_TAG => {
+                let bytes: [u8; 33] = read_array(reader)?;
+                VerifyingKey::from_sec1_bytes(&bytes)
+                    .map(PublicKey::Ecdsa)
+                    .map_err(|_| invalid_data("invalid ECDSA public key"))
+            }
+            SCHNORR_TAG => {
+                let bytes: [u8; 32] = read_array(reader)?;
+                XOnlyPublicKey::from_bytes(&bytes)
+                    .map(PublicKey::Schnorr)
+                    .ok_or_else(|| invalid_data("invalid x-only public key"))
+            }
+            _ => Err(invalid_data("unknown public key type")),
+        }
+    }
+}
+
+/// tag byte, then 64 bytes: r || s for both schemes
+impl Encodable for Signature {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        match self {
+            Signature::Ecdsa(signature) => {
+                writer.write_all(&[ECDSA_TAG])?;
+                writer.write_all(&signature.to_bytes())
+            }
+            Signature::Schnorr(signature) => {
+                writer.write_all(&[SCHNORR_TAG])?;
+                writer.write_all(&signature.0.to_bytes())
+            }
+        }
+    }
+}
+
+impl Decodable for Signature {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        let tag = u8::consensus_decode(reader)?;
+        let bytes: [u8; 64] = read_array(reader)?;
+        match tag {
+            ECDSA_TAG => ECDSASignature::from_slice(&bytes)
+                .map(Signature::Ecdsa)
+                .map_err(|_| invalid_data("invalid ECDSA signature")),
+            SCHNORR_TAG => schnorr::Signature::try_from(bytes.as_slice())
+                .map(|signature| Signature::Schnorr(SchnorrSignature(signature)))
+                .map_err(|_| invalid_data("invalid Schnorr signature")),
+            _ => Err(invalid_data("unknown signature type")),
+        }
+    }
+}
+
+impl Encodable for CompactTarget {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.0.consensus_encode(writer)
+    }
+}
+
+impl Decodable for CompactTarget {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(CompactTarget(u32::consensus_decode(reader)?))
+    }
+}
+
+fn decode_version<R: Read>(reader: &mut R) -> IoResult<()> {
+    if u8::consensus_decode(reader)? != CONSENSUS_VERSION {
+        return Err(invalid_data("unknown consensus version"));
+    }
+    Ok(())
+}
+
+/// The nonce goes last so miners can hash everything before it once
+/// (see `BlockHeader::midstate`)
+impl Encodable for BlockHeader {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        CONSENSUS_VERSION.consensus_encode(writer)?;
+        self.prev_block_hash.consensus_encode(writer)?;
+        self.merkle_root.consensus_encode(writer)?;
+        self.bits.consensus_encode(writer)?;
+        self.timestamp.consensus_encode(writer)?;
+        self.nonce.consensus_encode(writer)
+    }
+}
+
+impl Decodable for BlockHeader {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        decode_version(reader)?;
+        let prev_block_hash = Decodable::consensus_decode(reader)?;
+        let merkle_root = Decodable::consensus_decode(reader)?;
+        let bits = Decodable::consensus_decode(reader)?;
+        let timestamp = Decodable::consensus_decode(reader)?;
+        let nonce = Decodable::consensus_decode(reader)?;
+        Ok(BlockHeader {
+            timestamp,
+            nonce,
+            prev_block_hash,
+            merkle_root,
+            bits,
+        })
+    }
+}
+
+impl Encodable for TransactionInput {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.prev_transaction_output_hash.consensus_encode(writer)?;
+        self.signature.consensus_encode(writer)
+    }
+}
+
+impl Decodable for TransactionInput {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(TransactionInput {
+            prev_transaction_output_hash: Decodable::consensus_decode(reader)?,
+            signature: Decodable::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for TransactionOutput {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.value.consensus_encode(writer)?;
+        self.unique_id.consensus_encode(writer)?;
+        self.pubkey.consensus_encode(writer)
+    }
+}
+
+impl Decodable for TransactionOutput {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(TransactionOutput {
+            value: Decodable::consensus_decode(reader)?,
+            unique_id: Decodable::consensus_decode(reader)?,
+            pubkey: Decodable::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for Transaction {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        CONSENSUS_VERSION.consensus_encode(writer)?;
+        self.inputs.consensus_encode(writer)?;
+        self.outputs.consensus_encode(writer)
+    }
+}
+
+impl Decodable for Transaction {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        decode_version(reader)?;
+        Ok(Transaction {
+            inputs: Decodable::consensus_decode(reader)?,
+            outputs: Decodable::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for Block {
+    fn consensus_encode<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        self.header.consensus_encode(writer)?;
+        self.transactions.consensus_encode(writer)
+    }
+}
+
+impl Decodable for Block {
+    fn consensus_decode<R: Read>(reader: &mut R) -> IoResult<Self> {
+        Ok(Block {
+            header: Decodable::consensus_decode(reader)?,
+            transactions: Decodable::consensus_decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::params::ChainParams;
+
+    #[test]
+    fn varint() {
+        for (value, len) in [
+            (0, 1),
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x1_0000, 5),
+            (u64::MAX, 9),
+        ] {
+            let bytes = VarInt(value).consensus_bytes();
+            assert_eq!(bytes.len(), len);
+            assert_eq!(VarInt::from_consensus_bytes(&bytes).unwrap(), VarInt(value));
+        }
+        // 1 encoded in 3 bytes
+        assert!(VarInt::from_consensus_bytes(&[0xfd, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let private_key = PrivateKey::new_key();
+        let mut block = ChainParams::regtest().genesis_block();
+        let output = block.transactions[0].outputs[0].clone();
+        block.transactions.push(Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_schnorr(&output.hash(), &private_key),
+            }],
+            vec![TransactionOutput {
+                value: output.value,
+                unique_id: Uuid::new_v4(),
+                pubkey: private_key.x_only_public_key(),
+            }],
+        ));
+
+        let bytes = block.consensus_bytes();
+        let decoded = Block::from_consensus_bytes(&bytes).unwrap();
+        assert_eq!(decoded.consensus_bytes(), bytes);
+        assert_eq!(decoded.hash(), block.hash());
+        assert_eq!(decoded.transactions[1].hash(), block.transactions[1].hash());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut bytes = ChainParams::regtest()
+            .genesis_block()
+            .header
+            .consensus_bytes();
+        bytes[0] = CONSENSUS_VERSION + 1;
+        assert!(BlockHeader::from_consensus_bytes(&bytes).is_err());
+    }
+}
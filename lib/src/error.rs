@@ -1,5 +1,11 @@
+use chrono::{DateTime, Utc};
 use thiserror::Error;
 
+use crate::amount::Amount;
+use crate::sha256::Hash;
+use crate::target::CompactTarget;
+use crate::util::MerkleRoot;
+
 #[derive(Error, Debug)]
 pub enum BtcError {
     #[error("Invalid transaction")]
@@ -14,7 +20,7 @@ pub enum BtcError {
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
 
-    #[error("InvalidT transaction output")]
+    #[error("Invalid transaction output")]
     InvalidTransactionOutput,
 
     #[error("Invalid merkle root")]
@@ -31,6 +37,113 @@ pub enum BtcError {
 
     #[error("Invalid private key")]
     InvalidPrivateKey,
+
+    #[error("Invalid amount")]
+    InvalidAmount,
+
+    #[error("Merkle tree of no transactions")]
+    EmptyMerkleTree,
+
+    #[error("Mutated merkle tree: equal hashes paired")]
+    MutatedMerkleTree,
+
+    // transaction validation
+    #[error("Transaction {tx} input {input}: unknown output {output}")]
+    MissingInput {
+        tx: Hash,
+        input: usize,
+        output: Hash,
+    },
+
+    #[error("Transaction {tx} input {input}: output {output} is already spent")]
+    DuplicateInput {
+        tx: Hash,
+        input: usize,
+        output: Hash,
+    },
+
+    #[error("Transaction {tx} input {input}: bad signature")]
+    BadSignature { tx: Hash, input: usize },
+
+    #[error("Transaction {tx} output {output}: duplicate output")]
+    DuplicateOutput { tx: Hash, output: usize },
+
+    #[error("Transaction {tx}: values exceed the money supply")]
+    ValueOutOfRange { tx: Hash },
+
+    #[error("Transaction {tx}: spends {outputs} but its inputs are only {inputs}")]
+    Overspend {
+        tx: Hash,
+        inputs: Amount,
+        outputs: Amount,
+    },
+
+    #[error("Coinbase {tx}: {reason}")]
+    MalformedCoinbase { tx: Hash, reason: &'static str },
+
+    #[error("Coinbase {tx}: pays {actual}, expected {expected}")]
+    CoinbaseMismatch {
+        tx: Hash,
+        expected: Amount,
+        actual: Amount,
+    },
+
+    #[error("Transaction {tx}: {size} bytes is over the size limit")]
+    TransactionTooLarge { tx: Hash, size: usize },
+
+    // block validation
+    #[error("Block {block}: {size} bytes is over the size limit")]
+    BlockTooLarge { block: Hash, size: usize },
+
+    #[error("Block {block}: no transactions")]
+    EmptyBlock { block: Hash },
+
+    #[error("Block {block}: transaction {tx} appears more than once")]
+    DuplicateTransaction { block: Hash, tx: Hash },
+
+    #[error("Block {block}: merkle root {found} does not match transactions ({expected})")]
+    BadMerkleRoot {
+        block: Hash,
+        expected: MerkleRoot,
+        found: MerkleRoot,
+    },
+
+    #[error("Compact block {block}: prefilled index {index} is out of range or out of order")]
+    BadPrefilledIndex { block: Hash, index: u32 },
+
+    #[error("Block {block}: target {found}, expected {expected}")]
+    WrongTarget {
+        block: Hash,
+        expected: CompactTarget,
+        found: CompactTarget,
+    },
+
+    #[error("Block {block}: hash does not meet target {target}")]
+    InsufficientProofOfWork { block: Hash, target: CompactTarget },
+
+    #[error("Block {block}: previous block {found} is not the tip {expected}")]
+    PrevBlockMismatch {
+        block: Hash,
+        expected: Hash,
+        found: Hash,
+    },
+
+    #[error("Block {block}: timestamp {timestamp} is not after median time past {median}")]
+    TimestampTooOld {
+        block: Hash,
+        timestamp: DateTime<Utc>,
+        median: DateTime<Utc>,
+    },
+
+    #[error("Block {block}: timestamp {timestamp} is later than {max}")]
+    TimestampTooNew {
+        block: Hash,
+        timestamp: DateTime<Utc>,
+        max: DateTime<Utc>,
+    },
+
+    #[error("Block {block}: not the genesis block of this network")]
+    BadGenesis { block: Hash },
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
@@ -0,0 +1,323 @@
+//! Read-only block explorer: plain HTML pages for recent blocks, blocks,
+//! transactions and public keys, served from the chain and a `ChainIndex`.
+//!
+//! Routes: `/`, `/block/<hash or height>`, `/tx/<txid>` and
+//! `/pubkey/<hex of the consensus-encoded key>`.
+
+use std::fmt::Write;
+
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::encode::{Decodable, Encodable};
+use crate::http::{escape, Request, Response};
+use crate::index::ChainIndex;
+use crate::rpc::hex_hash;
+use crate::sha256::Hash;
+use crate::types::{Block, Blockchain, Transaction};
+
+/// Blocks listed on the front page
+pub const RECENT_BLOCKS: usize = 25;
+
+pub fn respond(blockchain: &Blockchain, index: &ChainIndex, request: &Request) -> Response {
+    if request.method != "GET" {
+        return Response::text(405, "the explorer is read-only");
+    }
+    let mut segments = request.path.trim_matches('/').splitn(2, '/');
+    let page = match (segments.next().unwrap_or(""), segments.next()) {
+        ("", None) => Some(recent_blocks(blockchain)),
+        ("block", Some(id)) => block_page(blockchain, id),
+        ("tx", Some(txid)) => transaction_page(blockchain, index, txid),
+        ("pubkey", Some(pubkey)) => pubkey_page(blockchain, index, pubkey),
+        _ => None,
+    };
+    match page {
+        Some(page) => Response::html(200, page),
+        None => Response::html(
+            404,
+            layout(
+                "Not found",
+                &format!("<p>Nothing at <code>{}</code>.</p>", escape(&request.path)),
+            ),
+        ),
+    }
+}
+
+fn recent_blocks(blockchain: &Blockchain) -> String {
+    let mut body = format!(
+        "<p>{} network, height {}, difficulty {}, {} transactions in the mempool</p>",
+        blockchain.params().network,
+        blockchain.block_height() - 1,
+        blockchain.difficulty(),
+        blockchain.mempool().len()
+    );
+    body.push_str("<table><tr><th>Height</th><th>Hash</th><th>Time</th><th>Transactions</th><th>Size</th></tr>");
+    let blocks: Vec<&Block> = blockchain.blocks().collect();
+    for (height, block) in blocks.iter().enumerate().rev().take(RECENT_BLOCKS) {
+        let _ = write!(
+            body,
+            "<tr><td>{height}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            block_link(&block.hash()),
+            time(block),
+            block.transactions.len(),
+            block.consensus_size()
+        );
+    }
+    body.push_str("</table>");
+    layout("Recent blocks", &body)
+}
+
+fn block_page(blockchain: &Blockchain, id: &str) -> Option<String> {
+    // a height, or a hash if it is as long as one
+    let (height, block) = match id.parse::<u64>() {
+        Ok(height) if id.len() < 64 => (height, blockchain.blocks().nth(height as usize)?),
+        _ => {
+            let hash: Hash = id.parse().ok()?;
+            let (height, block) = blockchain
+                .blocks()
+                .enumerate()
+                .find(|(_, block)| block.hash() == hash)?;
+            (height as u64, block)
+        }
+    };
+
+    let mut body = String::from("<table>");
+    let _ = write!(
+        body,
+        "<tr><th>Hash</th><td><code>{}</code></td></tr>\
+         <tr><th>Height</th><td>{height}</td></tr>\
+         <tr><th>Time</th><td>{}</td></tr>\
+         <tr><th>Previous block</th><td>{}</td></tr>\
+         <tr><th>Merkle root</th><td><code>{}</code></td></tr>\
+         <tr><th>Bits</th><td>{}</td></tr>\
+         <tr><th>Difficulty</th><td>{}</td></tr>\
+         <tr><th>Nonce</th><td>{}</td></tr>\
+         <tr><th>Size</th><td>{} bytes</td></tr>",
+        hex_hash(&block.hash()),
+        time(block),
+        match height {
+            0 => "none".to_string(),
+            _ => block_link(&block.header.prev_block_hash),
+        },
+        hex_hash(&block.header.merkle_root.0),
+        block.header.bits,
+        block.header.difficulty(),
+        block.header.nonce,
+        block.consensus_size()
+    );
+    if let Some(next) = blockchain.blocks().nth(height as usize + 1) {
+        let _ = write!(
+            body,
+            "<tr><th>Next block</th><td>{}</td></tr>",
+            block_link(&next.hash())
+        );
+    }
+    body.push_str("</table><h2>Transactions</h2><table><tr><th>Txid</th><th>Inputs</th><th>Outputs</th><th>Value</th></tr>");
+    for transaction in &block.transactions {
+        let _ = write!(
+            body,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            transaction_link(&transaction.hash()),
+            transaction.inputs.len(),
+            transaction.outputs.len(),
+            value(transaction.output_value())
+        );
+    }
+    body.push_str("</table>");
+    Some(layout(&format!("Block {height}"), &body))
+}
+
+fn transaction_page(blockchain: &Blockchain, index: &ChainIndex, txid: &str) -> Option<String> {
+    let txid: Hash = txid.parse().ok()?;
+    let (status, transaction): (String, &Transaction) =
+        match index.get_transaction(blockchain, &txid) {
+            Some((height, transaction)) => {
+                let block = blockchain.blocks().nth(height as usize)?;
+                let status = format!(
+                    "confirmed in block {} ({} confirmations)",
+                    block_link(&block.hash()),
+                    blockchain.block_height() - height
+                );
+                (status, transaction)
+            }
+            None => {
+                let (_, transaction) = blockchain
+                    .mempool()
+                    .iter()
+                    .find(|(_, transaction)| transaction.hash() == txid)?;
+                ("unconfirmed, in the mempool".to_string(), transaction)
+            }
+        };
+
+    let mut body = format!(
+        "<table><tr><th>Txid</th><td><code>{}</code></td></tr>\
+         <tr><th>Status</th><td>{status}</td></tr>\
+         <tr><th>Size</th><td>{} bytes</td></tr></table>",
+        hex_hash(&txid),
+        transaction.consensus_size()
+    );
+
+    body.push_str("<h2>Inputs</h2>");
+    if transaction.inputs.is_empty() {
+        body.push_str("<p>Coinbase: newly minted coins and fees</p>");
+    } else {
+        body.push_str("<table><tr><th>Spends</th><th>Value</th><th>Public key</th></tr>");
+        for input in &transaction.inputs {
+            let output = input.prev_transaction_output_hash;
+            let spends = match index.output(&output) {
+                Some((txid, n)) => format!(
+                    "<a href=\"/tx/{0}#output-{n}\">{0}:{n}</a>",
+                    hex_hash(&txid)
+                ),
+                None => format!("<code>{}</code>", hex_hash(&output)),
+            };
+            let (amount, pubkey) = match index.get_output(blockchain, &output) {
+                Some(output) => (output.value.to_string(), pubkey_link(&output.pubkey)),
+                None => ("unknown".to_string(), "unknown".to_string()),
+            };
+            let _ = write!(
+                body,
+                "<tr><td>{spends}</td><td>{amount}</td><td>{pubkey}</td></tr>"
+            );
+        }
+        body.push_str("</table>");
+    }
+
+    body.push_str(
+        "<h2>Outputs</h2><table><tr><th>#</th><th>Value</th><th>Public key</th><th>Spent by</th></tr>",
+    );
+    for (n, output) in transaction.outputs.iter().enumerate() {
+        let spent_by = match index.spent_by(&output.hash()) {
+            Some(txid) => transaction_link(&txid),
+            None => "unspent".to_string(),
+        };
+        let _ = write!(
+            body,
+            "<tr id=\"output-{n}\"><td>{n}</td><td>{}</td><td>{}</td><td>{spent_by}</td></tr>",
+            output.value,
+            pubkey_link(&output.pubkey)
+        );
+    }
+    body.push_str("</table>");
+    Some(layout("Transaction", &body))
+}
+
+fn pubkey_page(blockchain: &Blockchain, index: &ChainIndex, pubkey: &str) -> Option<String> {
+    let pubkey = PublicKey::from_consensus_bytes(&hex::decode(pubkey).ok()?).ok()?;
+
+    let unspent: Vec<(Hash, Amount)> = index
+        .unspent(&pubkey)
+        .into_iter()
+        .filter_map(|output| Some((output, index.get_output(blockchain, &output)?.value)))
+        .collect();
+    let balance = Amount::checked_sum(unspent.iter().map(|(_, value)| *value));
+
+    let mut body = format!(
+        "<table><tr><th>Public key</th><td><code>{}</code></td></tr>\
+         <tr><th>Balance</th><td>{}</td></tr></table>",
+        hex::encode(pubkey.consensus_bytes()),
+        value(balance)
+    );
+
+    body.push_str("<h2>Unspent outputs</h2><table><tr><th>Output</th><th>Value</th></tr>");
+    for (output, amount) in &unspent {
+        if let Some((txid, n)) = index.output(output) {
+            let _ = write!(
+                body,
+                "<tr><td><a href=\"/tx/{0}#output-{n}\">{0}:{n}</a></td><td>{amount}</td></tr>",
+                hex_hash(&txid)
+            );
+        }
+    }
+    body.push_str("</table>");
+
+    body.push_str("<h2>History</h2><table><tr><th>Height</th><th>Transaction</th></tr>");
+    for txid in index.history(&pubkey).iter().rev() {
+        let height = index
+            .transaction(txid)
+            .map(|(height, _)| height.to_string())
+            .unwrap_or_default();
+        let _ = write!(
+            body,
+            "<tr><td>{height}</td><td>{}</td></tr>",
+            transaction_link(txid)
+        );
+    }
+    body.push_str("</table>");
+    Some(layout("Public key", &body))
+}
+
+fn layout(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}table{{border-collapse:collapse}}\
+         td,th{{padding:.2em .8em;text-align:left}}code,td a{{font-family:monospace}}</style>\
+         </head><body><p><a href=\"/\">Explorer</a></p><h1>{title}</h1>{body}</body></html>"
+    )
+}
+
+fn time(block: &Block) -> String {
+    block
+        .header
+        .timestamp
+        .format("%Y-%m-%d %H:%M:%S UTC")
+        .to_string()
+}
+
+fn value(amount: Option<Amount>) -> String {
+    amount.map_or("out of range".to_string(), |amount| amount.to_string())
+}
+
+fn block_link(hash: &Hash) -> String {
+    format!("<a href=\"/block/{0}\">{0}</a>", hex_hash(hash))
+}
+
+fn transaction_link(txid: &Hash) -> String {
+    format!("<a href=\"/tx/{0}\">{0}</a>", hex_hash(txid))
+}
+
+fn pubkey_link(pubkey: &PublicKey) -> String {
+    format!(
+        "<a href=\"/pubkey/{0}\">{0}</a>",
+        hex::encode(pubkey.consensus_bytes())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+
+    fn get(blockchain: &Blockchain, path: &str) -> Response {
+        let raw = format!("GET {path} HTTP/1.1\r\n\r\n");
+        let request = Request::read(&mut raw.as_bytes()).unwrap();
+        respond(blockchain, &ChainIndex::new(blockchain), &request)
+    }
+
+    #[test]
+    fn links_genesis() {
+        let blockchain = Blockchain::new(ChainParams::regtest());
+        let genesis = blockchain.blocks().next().unwrap();
+        let coinbase = &genesis.transactions[0];
+
+        let home = String::from_utf8(get(&blockchain, "/").body).unwrap();
+        assert!(home.contains(&format!("/block/{}", hex_hash(&genesis.hash()))));
+
+        let block = get(&blockchain, "/block/0");
+        assert_eq!(block.status, 200);
+        assert!(String::from_utf8(block.body)
+            .unwrap()
+            .contains(&format!("/tx/{}", hex_hash(&coinbase.hash()))));
+
+        let tx = get(&blockchain, &format!("/tx/{}", hex_hash(&coinbase.hash())));
+        let pubkey = hex::encode(coinbase.outputs[0].pubkey.consensus_bytes());
+        assert!(String::from_utf8(tx.body)
+            .unwrap()
+            .contains(&format!("/pubkey/{pubkey}")));
+
+        let page = get(&blockchain, &format!("/pubkey/{pubkey}"));
+        assert!(String::from_utf8(page.body).unwrap().contains("50 BTC"));
+
+        assert_eq!(get(&blockchain, "/block/1").status, 404);
+        assert_eq!(get(&blockchain, "/tx/<script>").status, 404);
+    }
+}
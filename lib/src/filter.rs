@@ -0,0 +1,239 @@
+//! BIP158-style compact block filters.
+//!
+//! A block's filter is a Golomb-coded set over the consensus encoding of
+//! every output public key and every spent output hash in the block. Light
+//! wallets download filters and test their own keys and outputs locally,
+//! only fetching the blocks that match.
+
+use std::collections::BTreeSet;
+use std::hash::Hasher;
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+
+use crate::crypto::PublicKey;
+use crate::encode::{Decodable, Encodable, VarInt};
+use crate::sha256::Hash;
+use crate::types::Block;
+
+/// Golomb-Rice parameter, bits of remainder per element
+const P: u8 = 19;
+/// Inverse false positive rate
+const M: u64 = 784_931;
+
+/// Most filters or filter headers sent in one message
+pub const MAX_FILTERS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter {
+    /// Varint element count followed by the Golomb-Rice coded deltas
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn build(block: &Block) -> BlockFilter {
+        let elements: BTreeSet<Vec<u8>> = block
+            .transactions
+            .iter()
+            .flat_map(|transaction| {
+                let spent = transaction
+                    .inputs
+                    .iter()
+                    .map(|input| input.prev_transaction_output_hash.consensus_bytes());
+                let pubkeys = transaction
+                    .outputs
+                    .iter()
+                    .map(|output| output.pubkey.consensus_bytes());
+                spent.chain(pubkeys)
+            })
+            .collect();
+
+        let hasher = FilterHasher::new(&block.hash(), elements.len() as u64);
+        let mut values: Vec<u64> = elements.iter().map(|e| hasher.hash(e)).collect();
+        values.sort_unstable();
+
+        let mut content = VarInt(values.len() as u64).consensus_bytes();
+        let mut writer = BitWriter::new(&mut content);
+        let mut last = 0;
+        for value in values {
+            writer.write_golomb_rice(value - last);
+            last = value;
+        }
+        writer.flush();
+
+        BlockFilter { content }
+    }
+
+    /// Whether the block may pay to any of `pubkeys` or spend any of
+    /// `outputs`. False positives happen about once in `M` queries.
+    pub fn matches_any(&self, block: &Hash, pubkeys: &[PublicKey], outputs: &[Hash]) -> bool {
+        let mut reader = &self.content[..];
+        let count = match VarInt::consensus_decode(&mut reader) {
+            Ok(VarInt(count)) => count,
+            Err(_) => return false,
+        };
+
+        let hasher = FilterHasher::new(block, count);
+        let mut queries: Vec<u64> = pubkeys
+            .iter()
+            .map(|pubkey| hasher.hash(&pubkey.consensus_bytes()))
+            .chain(
+                outputs
+                    .iter()
+                    .map(|output| hasher.hash(&output.consensus_bytes())),
+            )
+            .collect();
+        queries.sort_unstable();
+
+        // walk both sorted lists together
+        let mut bits = BitReader::new(reader);
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..count {
+            value += match bits.read_golomb_rice() {
+                Some(delta) => delta,
+                None => return false,
+            };
+            while let Some(&query) = queries.peek() {
+                if query == value {
+                    return true;
+                } else if query < value {
+                    queries.next();
+                } else {
+                    break;
+                }
+            }
+            if queries.peek().is_none() {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Commits to this filter and, through `prev_header`, every earlier one;
+    /// the header before the genesis filter is zero
+    pub fn header(&self, prev_header: &Hash) -> Hash {
+        let mut bytes = Hash::hash_bytes(&self.content).consensus_bytes();
+        prev_header.consensus_encode(&mut bytes).unwrap();
+        Hash::hash_bytes(&bytes)
+    }
+}
+
+// SipHash-2-4 keyed by the block hash, mapped uniformly onto [0, N * M)
+struct FilterHasher {
+    k0: u64,
+    k1: u64,
+    range: u64,
+}
+
+impl FilterHasher {
+    fn new(block: &Hash, count: u64) -> Self {
+        let key = block.as_bytes();
+        FilterHasher {
+            k0: u64::from_le_bytes(key[0..8].try_into().unwrap()),
+            k1: u64::from_le_bytes(key[8..16].try_into().unwrap()),
+            range: count.saturating_mul(M),
+        }
+    }
+
+    fn hash(&self, element: &[u8]) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.k0, self.k1);
+        hasher.write(element);
+        ((hasher.finish() as u128 * self.range as u128) >> 64) as u64
+    }
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    byte: u8,
+    used: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            byte: 0,
+            used: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        self.byte |= (bit as u8) << (7 - self.used);
+        self.used += 1;
+        if self.used == 8 {
+            self.flush();
+        }
+    }
+
+    // quotient in unary, remainder in P bits, most significant first
+    fn write_golomb_rice(&mut self, value: u64) {
+        for _ in 0..value >> P {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for i in (0..P).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.used > 0 {
+            self.out.push(self.byte);
+            self.byte = 0;
+            self.used = 0;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, position: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = (byte >> (7 - self.position % 8)) & 1 == 1;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read_golomb_rice(&mut self) -> Option<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0;
+        for _ in 0..P {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Some((quotient << P) | remainder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PrivateKey;
+    use crate::params::ChainParams;
+
+    #[test]
+    fn matches_block_contents() {
+        let block = ChainParams::regtest().genesis_block();
+        let filter = BlockFilter::build(&block);
+        let block_hash = block.hash();
+        let pubkey = block.transactions[0].outputs[0].pubkey.clone();
+
+        assert!(filter.matches_any(&block_hash, std::slice::from_ref(&pubkey), &[]));
+        let stranger = PrivateKey::new_key().public_key();
+        assert!(!filter.matches_any(&block_hash, std::slice::from_ref(&stranger), &[]));
+        assert!(filter.matches_any(&block_hash, &[stranger, pubkey], &[]));
+        assert!(!filter.matches_any(&block_hash, &[], &[Hash::zero()]));
+        assert!(!filter.matches_any(&block_hash, &[], &[]));
+    }
+}
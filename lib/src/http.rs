@@ -0,0 +1,234 @@
+//! Just enough HTTP/1.1 for the node's JSON-RPC and explorer: one request
+//! per connection, `Content-Length` bodies only.
+
+use std::io::{
+    BufRead, Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write,
+};
+
+use base64::Engine;
+
+// request line or header, in bytes
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// a submitted block, hex encoded, with room to spare
+const MAX_BODY: usize = 4 * crate::MAX_BLOCK_SIZE;
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Read one request; lines, header count and body size are bounded so
+    /// a client cannot make the server buffer without limit
+    pub fn read<R: BufRead>(reader: &mut R) -> IoResult<Request> {
+        let line = read_line(reader)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Err(invalid_data("malformed request line"));
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let method = method.to_string();
+
+        let mut headers = vec![];
+        loop {
+            let line = read_line(reader)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == MAX_HEADERS {
+                return Err(invalid_data("too many headers"));
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid_data("malformed header"))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+
+        let mut request = Request {
+            method,
+            path,
+            query,
+            headers,
+            body: vec![],
+        };
+        let length = match request.header("content-length") {
+            Some(length) => length
+                .parse::<usize>()
+                .map_err(|_| invalid_data("bad content length"))?,
+            None => 0,
+        };
+        if length > MAX_BODY {
+            return Err(invalid_data("body too large"));
+        }
+        // grows as the body arrives instead of trusting the announced length
+        reader.take(length as u64).read_to_end(&mut request.body)?;
+        if request.body.len() < length {
+            return Err(IoError::from(IoErrorKind::UnexpectedEof));
+        }
+        Ok(request)
+    }
+
+    /// Header value, `name` in lowercase
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// User and password of `Authorization: Basic`
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let encoded = self.header("authorization")?.strip_prefix("Basic ")?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.to_string(), password.to_string()))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn json(status: u16, value: &serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    pub fn html(status: u16, page: String) -> Self {
+        Response {
+            status,
+            content_type: "text/html; charset=utf-8",
+            body: page.into_bytes(),
+        }
+    }
+
+    pub fn text(status: u16, text: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: text.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> IoResult<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        if self.status == 401 {
+            head.push_str("WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n");
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(&self.body)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+// a line of at most MAX_LINE bytes, with its line ending
+fn read_line<R: BufRead>(reader: &mut R) -> IoResult<String> {
+    let mut line = String::new();
+    reader.take(MAX_LINE as u64).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(match line.len() {
+            MAX_LINE => invalid_data("line too long"),
+            _ => IoError::from(IoErrorKind::UnexpectedEof),
+        });
+    }
+    Ok(line)
+}
+
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_string())
+}
+
+/// Escape text for HTML element content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_request() {
+        let raw = "POST /rpc?x=1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic dXNlcjpwYXNz\r\nContent-Length: 4\r\n\r\nbodyextra";
+        let request = Request::read(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/rpc");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(request.body, b"body");
+        assert_eq!(
+            request.basic_auth(),
+            Some(("user".to_string(), "pass".to_string()))
+        );
+    }
+
+    #[test]
+    fn bounds_requests() {
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        let error = Request::read(&mut long.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::InvalidData);
+
+        let headers = "X: y\r\n".repeat(MAX_HEADERS + 1);
+        let many = format!("GET / HTTP/1.1\r\n{headers}\r\n");
+        assert!(Request::read(&mut many.as_bytes()).is_err());
+
+        let large = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1
+        );
+        assert!(Request::read(&mut large.as_bytes()).is_err());
+        let short = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nbody";
+        let error = Request::read(&mut short.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), IoErrorKind::UnexpectedEof);
+    }
+}
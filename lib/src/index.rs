@@ -0,0 +1,185 @@
+//! Lookups the chain itself does not keep: where a transaction is, which
+//! transaction created or spent an output, and everything touching a
+//! public key. Built from `Blockchain::blocks()` and extended as blocks
+//! are added; it is not persisted.
+
+use std::collections::HashMap;
+
+use crate::crypto::PublicKey;
+use crate::encode::Encodable;
+use crate::sha256::Hash;
+use crate::types::{Blockchain, Transaction, TransactionOutput};
+
+#[derive(Clone, Debug, Default)]
+pub struct ChainIndex {
+    // number of blocks indexed
+    height: u64,
+    // txid -> (block height, position in the block)
+    transactions: HashMap<Hash, (u64, usize)>,
+    // output hash -> (txid, output index, consensus-encoded pubkey)
+    outputs: HashMap<Hash, (Hash, usize, Vec<u8>)>,
+    // output hash -> txid of the spending transaction
+    spends: HashMap<Hash, Hash>,
+    // pubkey -> txids paying to or spending from it, in chain order
+    history: HashMap<Vec<u8>, Vec<Hash>>,
+    // pubkey -> outputs paying to it, in chain order
+    received: HashMap<Vec<u8>, Vec<Hash>>,
+}
+
+impl ChainIndex {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        let mut index = ChainIndex::default();
+        index.update(blockchain);
+        index
+    }
+
+    /// Index the blocks added since the last update
+    pub fn update(&mut self, blockchain: &Blockchain) {
+        for (height, block) in blockchain.blocks().enumerate().skip(self.height as usize) {
+            for (position, transaction) in block.transactions.iter().enumerate() {
+                self.insert(height as u64, position, transaction);
+            }
+        }
+        self.height = blockchain.block_height();
+    }
+
+    fn insert(&mut self, height: u64, position: usize, transaction: &Transaction) {
+        let txid = transaction.hash();
+        self.transactions.insert(txid, (height, position));
+
+        let mut touched = vec![];
+        for input in &transaction.inputs {
+            let output = input.prev_transaction_output_hash;
+            self.spends.insert(output, txid);
+            if let Some((_, _, pubkey)) = self.outputs.get(&output) {
+                touched.push(pubkey.clone());
+            }
+        }
+        for (n, output) in transaction.outputs.iter().enumerate() {
+            let hash = output.hash();
+            let pubkey = output.pubkey.consensus_bytes();
+            self.outputs.insert(hash, (txid, n, pubkey.clone()));
+            self.received.entry(pubkey.clone()).or_default().push(hash);
+            touched.push(pubkey);
+        }
+
+        touched.sort_unstable();
+        touched.dedup();
+        for pubkey in touched {
+            self.history.entry(pubkey).or_default().push(txid);
+        }
+    }
+
+    /// Block height and position of a confirmed transaction
+    pub fn transaction(&self, txid: &Hash) -> Option<(u64, usize)> {
+        self.transactions.get(txid).copied()
+    }
+
+    /// A confirmed transaction and the height of its block
+    pub fn get_transaction<'a>(
+        &self,
+        blockchain: &'a Blockchain,
+        txid: &Hash,
+    ) -> Option<(u64, &'a Transaction)> {
+        let (height, position) = self.transaction(txid)?;
+        let block = blockchain.blocks().nth(height as usize)?;
+        Some((height, block.transactions.get(position)?))
+    }
+
+    /// Transaction and index that created an output
+    pub fn output(&self, output: &Hash) -> Option<(Hash, usize)> {
+        self.outputs.get(output).map(|(txid, n, _)| (*txid, *n))
+    }
+
+    pub fn get_output<'a>(
+        &self,
+        blockchain: &'a Blockchain,
+        output: &Hash,
+    ) -> Option<&'a TransactionOutput> {
+        let (txid, n) = self.output(output)?;
+        let (_, transaction) = self.get_transaction(blockchain, &txid)?;
+        transaction.outputs.get(n)
+    }
+
+    /// Confirmed transaction spending an output
+    pub fn spent_by(&self, output: &Hash) -> Option<Hash> {
+        self.spends.get(output).copied()
+    }
+
+    /// Confirmed transactions paying to or spending from `pubkey`, oldest first
+    pub fn history(&self, pubkey: &PublicKey) -> &[Hash] {
+        self.history
+            .get(&pubkey.consensus_bytes())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Outputs paying to `pubkey` that no confirmed transaction spends
+    pub fn unspent(&self, pubkey: &PublicKey) -> Vec<Hash> {
+        self.received
+            .get(&pubkey.consensus_bytes())
+            .into_iter()
+            .flatten()
+            .filter(|output| !self.spends.contains_key(output))
+            .copied()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::params::ChainParams;
+    use crate::rpc::block_template;
+    use crate::types::{Block, TransactionInput};
+
+    // mine the template paying `pubkey`, with whatever is in the mempool
+    fn mine(blockchain: &mut Blockchain, pubkey: PublicKey) -> Block {
+        let mut block = block_template(blockchain, pubkey).unwrap();
+        while !block.header.mine(100_000) {}
+        blockchain.add_block(block.clone()).unwrap();
+        block
+    }
+
+    #[test]
+    fn indexes_transactions_and_pubkeys() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let block = mine(&mut blockchain, alice.public_key());
+        let coinbase = block.transactions[0].hash();
+        let output = block.transactions[0].outputs[0].clone();
+        let mut index = ChainIndex::new(&blockchain);
+        assert_eq!(index.unspent(&alice.public_key()), vec![output.hash()]);
+
+        let transaction = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_for(&output.hash(), &alice, &output.pubkey),
+            }],
+            vec![TransactionOutput {
+                value: output.value,
+                unique_id: Uuid::new_v4(),
+                pubkey: alice.public_key(),
+            }],
+        );
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+        mine(&mut blockchain, bob.public_key());
+        index.update(&blockchain);
+
+        let txid = transaction.hash();
+        assert_eq!(index.transaction(&txid), Some((2, 1)));
+        assert_eq!(index.spent_by(&output.hash()), Some(txid));
+        assert_eq!(index.output(&output.hash()), Some((coinbase, 0)));
+        assert_eq!(index.history(&alice.public_key()), &[coinbase, txid]);
+        assert_eq!(
+            index.unspent(&alice.public_key()),
+            vec![transaction.outputs[0].hash()]
+        );
+        assert_eq!(index.history(&bob.public_key()).len(), 1);
+        assert!(index.get_transaction(&blockchain, &txid).is_some());
+    }
+}
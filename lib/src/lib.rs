@@ -1,3 +1,6 @@
+// construct_uint! expands to code these lints flag
+#![allow(clippy::manual_div_ceil, clippy::assign_op_pattern)]
+
 use serde::{Deserialize, Serialize};
 use uint::construct_uint;
 construct_uint! {
@@ -5,24 +8,42 @@ construct_uint! {
   pub struct U256(4);
 }
 
+pub mod amount;
 pub mod crypto;
+pub mod dandelion;
+pub mod difficulty;
+pub mod encode;
 pub mod error;
+pub mod explorer;
+pub mod filter;
+pub mod http;
+pub mod index;
 pub mod network;
+pub mod node;
+pub mod params;
+pub mod peer;
+pub mod proxy;
+pub mod rpc;
 pub mod sha256;
+pub mod target;
+pub mod transport;
 pub mod types;
 pub mod util;
 
-pub const INITIAL_REWARD: u64 = 50;
-pub const HALVING_INTERVAL: u64 = 210; // blocks
-pub const IDEAL_BLOCK_TIME: u64 = 10; // seconds
+// difficulty 1 target, the mainnet max target
 pub const MIN_TARGET: U256 = U256([
     0xFFFF_FFFF_FFFF_FFFF,
     0xFFFF_FFFF_FFFF_FFFF,
     0xFFFF_FFFF_FFFF_FFFF,
     0x0000_0FFF_FFFF_FFFF,
 ]);
-// difficulty update interval in blocks
-pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
 
-// max mempool trx age (seconds)
-pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
+// number of past blocks whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_PAST_WINDOW: usize = 11;
+// how far a block timestamp may run ahead of the network-adjusted clock (seconds)
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+// peer clock offsets beyond this are ignored by the network-adjusted clock (seconds)
+pub const MAX_PEER_TIME_OFFSET: u64 = 70 * 60;
+
+// maximum consensus-encoded size of a block, and so of any transaction (bytes)
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
@@ -1,12 +1,21 @@
 use std::io::{Error as IoError, Read, Write};
+use std::net::IpAddr;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     crypto::PublicKey,
-    types::{Block, Transaction, TransactionOutput},
+    filter::BlockFilter,
+    peer::{InventoryItem, PeerInfo},
+    sha256::Hash,
+    types::{Block, BlockHeader, CompactBlock, Transaction, TransactionOutput},
+    util::MerkleProof,
 };
 
+// a block is at most MAX_BLOCK_SIZE in consensus encoding, CBOR is larger
+const MAX_MESSAGE_SIZE: usize = 16 * crate::MAX_BLOCK_SIZE;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
     /// Fetch  all pubkey UTXOs
@@ -17,6 +26,8 @@ pub enum Message {
     SubmitTransaction(Transaction),
     /// Broadcast a new tx to other nodes
     NewTransaction(Transaction),
+    /// Pass a new tx along the Dandelion stem, to a single node
+    StemTransaction(Transaction),
     /// Ask the node to prepare the optimal block template with coinbase tx paying the specified pub key
     FetchTemplate(PublicKey),
     /// The Template
@@ -39,6 +50,53 @@ pub enum Message {
     FetchBlock(usize),
     /// Broadcast a new block to the other nodes
     NewBlock(Block),
+    /// Ask a node to prove that a transaction is in the chain
+    FetchMerkleProof(Hash),
+    /// Response of FetchMerkleProof: height, header and proof, None if the transaction is not in the chain
+    MerkleProof(Option<(u64, BlockHeader, MerkleProof)>),
+    /// Ask a node for the headers following the first known hash of a locator
+    GetHeaders(Vec<Hash>),
+    /// Response of GetHeaders, at most MAX_HEADERS, empty when the asker is up to date
+    Headers(Vec<BlockHeader>),
+    /// Ask a node for the compact filters of the blocks from the given height
+    GetFilters(u64),
+    /// Response of GetFilters: block hash and filter, at most MAX_FILTERS
+    Filters(Vec<(Hash, BlockFilter)>),
+    /// Ask a node for the filter headers of the blocks from the given height
+    GetFilterHeaders(u64),
+    /// Response of GetFilterHeaders, at most MAX_FILTERS
+    FilterHeaders(Vec<Hash>),
+    /// Announce a new block by header and short transaction ids
+    CompactBlock(CompactBlock),
+    /// Ask for the transactions of a compact block missing from the mempool, by index
+    GetBlockTransactions { block: Hash, indexes: Vec<u32> },
+    /// Response of GetBlockTransactions, in the requested order
+    BlockTransactions {
+        block: Hash,
+        transactions: Vec<Transaction>,
+    },
+    /// Announce blocks and transactions by hash
+    Inv(Vec<InventoryItem>),
+    /// Ask for announced objects, answered with NewTransaction and NewBlock
+    GetData(Vec<InventoryItem>),
+    /// Response of GetData for objects the node no longer has
+    NotFound(Vec<InventoryItem>),
+    /// Admin: ask a node for its active bans
+    ListBans,
+    /// Response of ListBans: address and end of the ban
+    Bans(Vec<(IpAddr, DateTime<Utc>)>),
+    /// Admin: lift the ban on one address, or all bans
+    ClearBans(Option<IpAddr>),
+    /// Sent first on every connection: the sender's clock, sampled for network-adjusted time
+    Version(DateTime<Utc>),
+    /// Liveness check, answered with a Pong carrying the same nonce
+    Ping(u64),
+    /// Response of Ping
+    Pong(u64),
+    /// Admin: ask a node for its connected peers
+    ListPeers,
+    /// Response of ListPeers
+    Peers(Vec<PeerInfo>),
 }
 
 impl Message {
@@ -64,6 +122,12 @@ impl Message {
         let mut len_bytes = [0u8; 8];
         stream.read_exact(&mut len_bytes)?;
         let len = u64::from_be_bytes(len_bytes) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            return Err(ciborium::de::Error::Io(IoError::new(
+                std::io::ErrorKind::InvalidData,
+                "message too large",
+            )));
+        }
         let mut data = vec![0u8; len];
         stream.read_exact(&mut data)?;
         Self::decode(&data)
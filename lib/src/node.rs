@@ -0,0 +1,1123 @@
+//! Peer-to-peer relay logic of a full node, without any I/O.
+//!
+//! The node binary owns the sockets: it reports connections and received
+//! messages to a `Node` and carries out the `Action`s it queues. New blocks
+//! are pushed as compact blocks, transactions announced by hash with `Inv`
+//! and fetched with `GetData`; nothing is announced twice to the same peer.
+//! New transactions first travel a Dandelion stem, see `crate::dandelion`,
+//! and only enter the mempool once fluffed.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::dandelion::{Dandelion, Route};
+use crate::error::Result;
+use crate::network::Message;
+use crate::peer::{
+    BanList, InventoryItem, Keepalive, KeepaliveAction, KnownInventory, Misbehaviour, PeerInfo,
+    PeerScore, DEFAULT_BAN_DURATION,
+};
+use crate::rpc::block_template;
+use crate::sha256::Hash;
+use crate::types::{Block, Blockchain, CompactBlock, PartialBlock, Transaction, MAX_HEADERS};
+
+/// Something the I/O layer has to do
+#[derive(Clone, Debug)]
+pub enum Action {
+    Send(SocketAddr, Message),
+    /// Close the connection, the node has forgotten the peer already
+    Disconnect(SocketAddr),
+}
+
+#[derive(Debug)]
+struct Peer {
+    // we opened the connection
+    outbound: bool,
+    known: KnownInventory,
+    // what we announced or sent to it, the only transactions it may fetch
+    sent: KnownInventory,
+    // its Version went into the network time already
+    time_sampled: bool,
+    score: PeerScore,
+    keepalive: Keepalive,
+    // compact block waiting for BlockTransactions
+    partial: Option<PartialBlock>,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    blockchain: Blockchain,
+    peers: HashMap<SocketAddr, Peer>,
+    // requested with GetData and not yet received, by the peer asked
+    in_flight: HashMap<InventoryItem, SocketAddr>,
+    bans: BanList,
+    dandelion: Dandelion,
+    actions: Vec<Action>,
+}
+
+impl Node {
+    pub fn new(blockchain: Blockchain) -> Self {
+        Node {
+            blockchain,
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+            bans: BanList::new(),
+            dandelion: Dandelion::new(),
+            actions: vec![],
+        }
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
+
+    pub fn blockchain_mut(&mut self) -> &mut Blockchain {
+        &mut self.blockchain
+    }
+
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    pub fn bans_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+
+    /// Ban `ip` for `duration` and drop the peers connected from it
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        self.bans.ban(ip, duration);
+        let banned: Vec<SocketAddr> = self
+            .peers
+            .keys()
+            .filter(|address| address.ip() == ip)
+            .copied()
+            .collect();
+        for address in banned {
+            self.disconnect(address);
+        }
+    }
+
+    /// Actions queued since the last call, in order
+    pub fn take_actions(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.actions)
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .peers
+            .iter()
+            .map(|(address, peer)| PeerInfo {
+                address: address.to_string(),
+                inbound: !peer.outbound,
+                rtt: peer.keepalive.rtt(),
+                misbehaviour: peer.score.score(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.address.cmp(&b.address));
+        peers
+    }
+
+    /// A connection was opened, by us if `outbound`
+    pub fn connected(&mut self, address: SocketAddr, outbound: bool, now: Instant) {
+        if self.bans.is_banned(&address.ip()) {
+            self.actions.push(Action::Disconnect(address));
+            return;
+        }
+        self.peers.insert(
+            address,
+            Peer {
+                outbound,
+                known: KnownInventory::new(),
+                sent: KnownInventory::new(),
+                time_sampled: false,
+                score: PeerScore::default(),
+                keepalive: Keepalive::new(now),
+                partial: None,
+            },
+        );
+        self.send(address, Message::Version(Utc::now()));
+        self.send(address, Message::GetHeaders(self.blockchain.locator()));
+    }
+
+    /// `address` sent bytes that do not decode as a `Message`
+    pub fn malformed(&mut self, address: SocketAddr) {
+        self.punish(address, Misbehaviour::MalformedMessage);
+    }
+
+    pub fn disconnected(&mut self, address: SocketAddr) {
+        if self
+            .peers
+            .remove(&address)
+            .is_some_and(|peer| peer.outbound)
+        {
+            // it may have been the stem successor
+            self.dandelion.reset_successor();
+        }
+        self.in_flight.retain(|_, peer| *peer != address);
+    }
+
+    /// Ping quiet peers and drop the ones that stopped answering; call
+    /// this every few seconds
+    pub fn tick(&mut self, now: Instant) {
+        let mut pings = vec![];
+        let mut silent = vec![];
+        for (address, peer) in &mut self.peers {
+            match peer.keepalive.poll(now) {
+                KeepaliveAction::Wait => {}
+                KeepaliveAction::SendPing(nonce) => pings.push((*address, nonce)),
+                KeepaliveAction::Disconnect => silent.push(*address),
+            }
+        }
+        for (address, nonce) in pings {
+            self.send(address, Message::Ping(nonce));
+        }
+        for address in silent {
+            self.disconnect(address);
+        }
+
+        // stems that did not come back fluffed in time
+        for transaction in self.dandelion.expired(now) {
+            self.fluff(transaction, None);
+        }
+    }
+
+    /// A wallet or RPC client submitted a transaction
+    pub fn submit_transaction(&mut self, transaction: Transaction, now: Instant) -> Result<()> {
+        self.blockchain.check_transaction(&transaction)?;
+        self.relay(transaction, None, now);
+        Ok(())
+    }
+
+    /// A miner or RPC client submitted a block
+    pub fn submit_block(&mut self, block: Block) -> Result<()> {
+        let item = InventoryItem::Block(block.hash());
+        self.blockchain.add_block(block)?;
+        self.announce(item, None);
+        Ok(())
+    }
+
+    pub fn receive(&mut self, address: SocketAddr, message: Message, now: Instant) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        peer.keepalive.received(now);
+        match message {
+            Message::Ping(nonce) => self.send(address, Message::Pong(nonce)),
+            // an expected pong is recorded by the guard and needs nothing else
+            Message::Pong(nonce) if !peer.keepalive.pong(nonce, now) => {
+                self.punish(address, Misbehaviour::Spam)
+            }
+            Message::Version(timestamp) => self.version(address, timestamp),
+            Message::Inv(items) => self.inv(address, items),
+            Message::GetData(items) => self.get_data(address, items),
+            Message::NotFound(items) => {
+                for item in items {
+                    self.in_flight.remove(&item);
+                }
+            }
+            Message::NewTransaction(transaction) => self.new_transaction(address, transaction),
+            Message::StemTransaction(transaction) => {
+                self.stem_transaction(address, transaction, now)
+            }
+            Message::NewBlock(block) => self.new_block(address, block),
+            Message::CompactBlock(compact) => self.compact_block(address, compact),
+            Message::GetBlockTransactions { block, indexes } => {
+                self.get_block_transactions(address, block, indexes)
+            }
+            Message::BlockTransactions {
+                block,
+                transactions,
+            } => self.block_transactions(address, block, transactions),
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain.headers_after(&locator);
+                self.send(address, Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                // fetched like announced blocks, in chain order
+                let items: Vec<InventoryItem> = headers
+                    .iter()
+                    .map(|header| InventoryItem::Block(header.hash()))
+                    .collect();
+                self.inv(address, items);
+                // a full batch means there are more to ask for
+                if let (MAX_HEADERS, Some(last)) = (headers.len(), headers.last()) {
+                    let mut locator = vec![last.hash()];
+                    locator.extend(self.blockchain.locator());
+                    self.send(address, Message::GetHeaders(locator));
+                }
+            }
+            Message::SubmitTransaction(transaction) => {
+                // a wallet gets no answer, it sees the result in its UTXOs
+                let _ = self.submit_transaction(transaction, now);
+            }
+            Message::FetchTemplate(pubkey) => {
+                if let Ok(template) = block_template(&self.blockchain, pubkey) {
+                    self.send(address, Message::Template(template));
+                }
+            }
+            Message::ValidateTemplate(block) => {
+                let valid = Some(block.header.prev_block_hash)
+                    == self.blockchain.blocks().last().map(Block::hash);
+                self.send(address, Message::TemplateValidity(valid));
+            }
+            Message::SubmitTemplate(block) => {
+                let accepted = self.submit_block(block).is_ok();
+                self.send(address, Message::TemplateValidity(accepted));
+            }
+            Message::FetchUTXO(pubkey) => {
+                let utxos = self
+                    .blockchain
+                    .utxos()
+                    .values()
+                    .filter(|(_, output)| output.pubkey == pubkey)
+                    .map(|(marked, output)| (output.clone(), *marked))
+                    .collect();
+                self.send(address, Message::UTXOs(utxos));
+            }
+            Message::DiscoverNodes => {
+                let nodes = self.peers.keys().map(|peer| peer.to_string()).collect();
+                self.send(address, Message::NodeList(nodes));
+            }
+            Message::AskDifference(height) => {
+                let difference = self.blockchain.block_height() as i64 - height as i64;
+                self.send(address, Message::Difference(difference as i32));
+            }
+            Message::FetchBlock(height) => {
+                let block = self.blockchain.blocks().nth(height).cloned();
+                if let Some(block) = block {
+                    self.send(address, Message::NewBlock(block));
+                }
+            }
+            Message::GetFilters(start) => {
+                let filters = self.blockchain.filters_from(start);
+                self.send(address, Message::Filters(filters));
+            }
+            Message::GetFilterHeaders(start) => {
+                let headers = self.blockchain.filter_headers_from(start);
+                self.send(address, Message::FilterHeaders(headers));
+            }
+            Message::FetchMerkleProof(transaction) => {
+                let proof = self
+                    .blockchain
+                    .merkle_proof(&transaction)
+                    .map(|(height, header, proof)| (height, header.clone(), proof));
+                self.send(address, Message::MerkleProof(proof));
+            }
+            // answers to requests a node does not make
+            _ => {}
+        }
+    }
+
+    // only peers we picked count, so inbound connections cannot skew our clock
+    fn version(&mut self, address: SocketAddr, timestamp: DateTime<Utc>) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        if peer.outbound && !peer.time_sampled {
+            peer.time_sampled = true;
+            self.blockchain.network_time_mut().add_sample(timestamp);
+        }
+    }
+
+    fn inv(&mut self, address: SocketAddr, items: Vec<InventoryItem>) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        for item in &items {
+            peer.known.insert(*item);
+        }
+        let wanted = items.into_iter().filter(|item| !self.has(item)).collect();
+        self.request(address, wanted);
+    }
+
+    fn get_data(&mut self, address: SocketAddr, items: Vec<InventoryItem>) {
+        let mut not_found = vec![];
+        for item in items {
+            // only what we told the peer about, so nobody can probe the
+            // mempool for transactions we never announced to them
+            let announced = self
+                .peers
+                .get(&address)
+                .is_some_and(|peer| peer.sent.contains(&item));
+            let message = match item {
+                InventoryItem::Transaction(_) if !announced => None,
+                InventoryItem::Transaction(hash) => self
+                    .mempool_transaction(&hash)
+                    .map(|transaction| Message::NewTransaction(transaction.clone())),
+                InventoryItem::Block(hash) => self
+                    .blockchain
+                    .block(&hash)
+                    .map(|block| Message::NewBlock(block.clone())),
+            };
+            match message {
+                Some(message) => {
+                    if let Some(peer) = self.peers.get_mut(&address) {
+                        peer.known.insert(item);
+                        peer.sent.insert(item);
+                    }
+                    self.send(address, message);
+                }
+                None => not_found.push(item),
+            }
+        }
+        if !not_found.is_empty() {
+            self.send(address, Message::NotFound(not_found));
+        }
+    }
+
+    fn new_transaction(&mut self, address: SocketAddr, transaction: Transaction) {
+        let item = InventoryItem::Transaction(transaction.hash());
+        self.in_flight.remove(&item);
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.known.insert(item);
+        }
+        // seen fluffed: a stem we are holding is over
+        self.dandelion.fluffed(&transaction.hash());
+        if self.has(&item) {
+            return;
+        }
+        match self.blockchain.add_to_mempool(transaction) {
+            Ok(()) => self.announce(item, Some(address)),
+            Err(e) => self.punish(address, Misbehaviour::Invalid(&e)),
+        }
+    }
+
+    fn stem_transaction(&mut self, address: SocketAddr, transaction: Transaction, now: Instant) {
+        let item = InventoryItem::Transaction(transaction.hash());
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.known.insert(item);
+        }
+        // a stem that loops back ends here, the embargo still covers it
+        if self.has(&item) || self.dandelion.stemmed(&transaction.hash()).is_some() {
+            return;
+        }
+        match self.blockchain.check_transaction(&transaction) {
+            Ok(()) => self.relay(transaction, Some(address), now),
+            Err(e) => self.punish(address, Misbehaviour::Invalid(&e)),
+        }
+    }
+
+    // pass a new transaction along the stem, or announce it
+    fn relay(&mut self, transaction: Transaction, from: Option<SocketAddr>, now: Instant) {
+        let hash = transaction.hash();
+        let item = InventoryItem::Transaction(hash);
+        let successors: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(address, peer)| peer.outbound && Some(**address) != from)
+            .map(|(address, _)| address.to_string())
+            .collect();
+        let route = self.dandelion.route(&transaction, &successors, now);
+        let successor = match route {
+            Route::Stem(successor) => successor.parse::<SocketAddr>().ok(),
+            Route::Fluff => None,
+        };
+        let Some(successor) = successor else {
+            self.dandelion.fluffed(&hash);
+            return self.fluff(transaction, from);
+        };
+        if let Some(peer) = self.peers.get_mut(&successor) {
+            peer.known.insert(item);
+        }
+        self.send(successor, Message::StemTransaction(transaction));
+    }
+
+    // move a checked transaction into the mempool and announce it
+    fn fluff(&mut self, transaction: Transaction, from: Option<SocketAddr>) {
+        let item = InventoryItem::Transaction(transaction.hash());
+        // already fluffed by someone else, or mined meanwhile
+        if !self.has(&item) && self.blockchain.add_to_mempool(transaction).is_ok() {
+            self.announce(item, from);
+        }
+    }
+
+    fn new_block(&mut self, address: SocketAddr, block: Block) {
+        let item = InventoryItem::Block(block.hash());
+        self.in_flight.remove(&item);
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.known.insert(item);
+        }
+        if self.has(&item) {
+            return;
+        }
+        // a block we cannot connect yet: catch up from the sender first
+        if self
+            .blockchain
+            .block(&block.header.prev_block_hash)
+            .is_none()
+        {
+            self.send(address, Message::GetHeaders(self.blockchain.locator()));
+            return;
+        }
+        self.accept_block(address, block);
+    }
+
+    fn compact_block(&mut self, address: SocketAddr, compact: CompactBlock) {
+        let hash = compact.header.hash();
+        let item = InventoryItem::Block(hash);
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.known.insert(item);
+        }
+        if self.has(&item) || self.in_flight.contains_key(&item) {
+            return;
+        }
+        if self
+            .blockchain
+            .block(&compact.header.prev_block_hash)
+            .is_none()
+        {
+            self.send(address, Message::GetHeaders(self.blockchain.locator()));
+            return;
+        }
+        // check the proof of work before spending effort on the body
+        if let Err(e) = self.blockchain.validate_header(&compact.header) {
+            self.punish(address, Misbehaviour::Invalid(&e));
+            return;
+        }
+        let mempool = self.blockchain.mempool().iter().map(|(_, tx)| tx);
+        let partial = match compact.reconstruct(mempool) {
+            Ok(partial) => partial,
+            Err(e) => return self.punish(address, Misbehaviour::Invalid(&e)),
+        };
+        let missing = partial.missing();
+        if missing.is_empty() {
+            return self.complete_block(address, partial);
+        }
+        if let Some(peer) = self.peers.get_mut(&address) {
+            peer.partial = Some(partial);
+        }
+        self.send(
+            address,
+            Message::GetBlockTransactions {
+                block: hash,
+                indexes: missing,
+            },
+        );
+    }
+
+    fn get_block_transactions(&mut self, address: SocketAddr, block: Hash, indexes: Vec<u32>) {
+        let Some(found) = self.blockchain.block(&block) else {
+            return self.send(
+                address,
+                Message::NotFound(vec![InventoryItem::Block(block)]),
+            );
+        };
+        let transactions: Option<Vec<Transaction>> = indexes
+            .iter()
+            .map(|index| found.transactions.get(*index as usize).cloned())
+            .collect();
+        match transactions {
+            Some(transactions) => self.send(
+                address,
+                Message::BlockTransactions {
+                    block,
+                    transactions,
+                },
+            ),
+            None => self.punish(address, Misbehaviour::MalformedMessage),
+        }
+    }
+
+    fn block_transactions(
+        &mut self,
+        address: SocketAddr,
+        block: Hash,
+        transactions: Vec<Transaction>,
+    ) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        let mut partial = match peer.partial.take() {
+            Some(partial) if partial.block_hash() == block => partial,
+            // a late answer, or one nobody asked for
+            other => {
+                peer.partial = other;
+                return;
+            }
+        };
+        if !partial.fill(transactions) {
+            return self.punish(address, Misbehaviour::MalformedMessage);
+        }
+        self.complete_block(address, partial);
+    }
+
+    // a body that does not match the merkle root means a short id
+    // collision, not a lie: the full block is fetched instead
+    fn complete_block(&mut self, address: SocketAddr, partial: PartialBlock) {
+        let item = InventoryItem::Block(partial.block_hash());
+        match partial.into_block() {
+            Some(block) => self.accept_block(address, block),
+            None => self.request(address, vec![item]),
+        }
+    }
+
+    fn accept_block(&mut self, address: SocketAddr, block: Block) {
+        let item = InventoryItem::Block(block.hash());
+        match self.blockchain.add_block(block) {
+            Ok(()) => self.announce(item, Some(address)),
+            Err(e) => self.punish(address, Misbehaviour::Invalid(&e)),
+        }
+    }
+
+    // ban once the score reaches BAN_THRESHOLD
+    fn punish(&mut self, address: SocketAddr, misbehaviour: Misbehaviour) {
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return;
+        };
+        if peer.score.punish(misbehaviour) {
+            self.ban(address.ip(), Duration::seconds(DEFAULT_BAN_DURATION));
+        }
+    }
+
+    fn disconnect(&mut self, address: SocketAddr) {
+        self.disconnected(address);
+        self.actions.push(Action::Disconnect(address));
+    }
+
+    // ask `address` for the items nobody is sending us yet
+    fn request(&mut self, address: SocketAddr, items: Vec<InventoryItem>) {
+        let items: Vec<InventoryItem> = items
+            .into_iter()
+            .filter(|item| !self.in_flight.contains_key(item))
+            .collect();
+        if items.is_empty() {
+            return;
+        }
+        for item in &items {
+            self.in_flight.insert(*item, address);
+        }
+        self.send(address, Message::GetData(items));
+    }
+
+    // tell every peer that does not know `item` yet, except `from`
+    fn announce(&mut self, item: InventoryItem, from: Option<SocketAddr>) {
+        let mut announcements = vec![];
+        for (address, peer) in &mut self.peers {
+            if Some(*address) != from && !peer.known.unknown(&[item]).is_empty() {
+                peer.sent.insert(item);
+                announcements.push(*address);
+            }
+        }
+        if announcements.is_empty() {
+            return;
+        }
+        let message = match item {
+            InventoryItem::Block(hash) => match self.blockchain.block(&hash) {
+                Some(block) => Message::CompactBlock(CompactBlock::new(block)),
+                None => return,
+            },
+            InventoryItem::Transaction(_) => Message::Inv(vec![item]),
+        };
+        for address in announcements {
+            self.send(address, message.clone());
+        }
+    }
+
+    fn has(&self, item: &InventoryItem) -> bool {
+        match item {
+            InventoryItem::Transaction(hash) => self.mempool_transaction(hash).is_some(),
+            InventoryItem::Block(hash) => self.blockchain.block(hash).is_some(),
+        }
+    }
+
+    fn mempool_transaction(&self, hash: &Hash) -> Option<&Transaction> {
+        self.blockchain
+            .mempool()
+            .iter()
+            .map(|(_, transaction)| transaction)
+            .find(|transaction| transaction.hash() == *hash)
+    }
+
+    fn send(&mut self, address: SocketAddr, message: Message) {
+        self.actions.push(Action::Send(address, message));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashSet;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::dandelion::{EMBARGO, EMBARGO_JITTER};
+    use crate::params::ChainParams;
+    use crate::peer::{PING_INTERVAL, PING_TIMEOUT};
+    use crate::types::{TransactionInput, TransactionOutput};
+
+    /// Nodes connected in memory, each under its own address
+    pub(crate) struct Network {
+        pub nodes: Vec<(SocketAddr, Node)>,
+        // every message delivered: (from, to, message)
+        pub log: Vec<(SocketAddr, SocketAddr, Message)>,
+    }
+
+    impl Network {
+        pub fn new(count: usize) -> Self {
+            let nodes = (0..count)
+                .map(|n| {
+                    let address = format!("10.0.0.{}:9333", n + 1).parse().unwrap();
+                    (address, Node::new(Blockchain::new(ChainParams::regtest())))
+                })
+                .collect();
+            Network { nodes, log: vec![] }
+        }
+
+        pub fn address(&self, n: usize) -> SocketAddr {
+            self.nodes[n].0
+        }
+
+        pub fn node(&mut self, n: usize) -> &mut Node {
+            &mut self.nodes[n].1
+        }
+
+        /// Node `from` opens a connection to node `to`
+        pub fn connect(&mut self, from: usize, to: usize, now: Instant) {
+            let (a, b) = (self.address(from), self.address(to));
+            self.node(from).connected(b, true, now);
+            self.node(to).connected(a, false, now);
+        }
+
+        /// Deliver messages until every outbox is empty
+        pub fn run(&mut self, now: Instant) {
+            loop {
+                let mut delivered = false;
+                for n in 0..self.nodes.len() {
+                    let from = self.address(n);
+                    for action in self.node(n).take_actions() {
+                        delivered = true;
+                        match action {
+                            Action::Send(to, message) => {
+                                self.log.push((from, to, message.clone()));
+                                if let Some(node) = self.find(to) {
+                                    node.receive(from, message, now);
+                                }
+                            }
+                            Action::Disconnect(to) => {
+                                if let Some(node) = self.find(to) {
+                                    node.disconnected(from);
+                                }
+                            }
+                        }
+                    }
+                }
+                if !delivered {
+                    return;
+                }
+            }
+        }
+
+        fn find(&mut self, address: SocketAddr) -> Option<&mut Node> {
+            self.nodes
+                .iter_mut()
+                .find(|(node, _)| *node == address)
+                .map(|(_, node)| node)
+        }
+
+        /// Messages sent to `to` matching `filter`
+        pub fn received(&self, to: usize, filter: fn(&Message) -> bool) -> usize {
+            let address = self.address(to);
+            self.log
+                .iter()
+                .filter(|(_, destination, message)| *destination == address && filter(message))
+                .count()
+        }
+    }
+
+    /// Mine the template of `node`, paying `key`
+    pub(crate) fn mine(node: &Node, key: &PrivateKey) -> Block {
+        let mut block = block_template(node.blockchain(), key.public_key()).unwrap();
+        while !block.header.mine(100_000) {}
+        block
+    }
+
+    pub(crate) fn spend(output: &TransactionOutput, key: &PrivateKey) -> Transaction {
+        Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_for(&output.hash(), key, &output.pubkey),
+            }],
+            vec![TransactionOutput {
+                value: output.value,
+                unique_id: Uuid::new_v4(),
+                pubkey: key.public_key(),
+            }],
+        )
+    }
+
+    #[test]
+    fn relays_by_inventory() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        // a line: 0 - 1 - 2, and 2 - 0 to close a loop
+        let mut network = Network::new(3);
+        for n in 0..3 {
+            // straight to the fluff phase
+            network.node(n).dandelion.fluff_probability = 1.0;
+        }
+        network.connect(0, 1, now);
+        network.connect(1, 2, now);
+        network.connect(2, 0, now);
+        network.run(now);
+
+        let block = mine(network.node(0), &key);
+        network.node(0).submit_block(block.clone()).unwrap();
+        network.run(now);
+        for n in 0..3 {
+            assert_eq!(network.node(n).blockchain().block_height(), 2);
+        }
+
+        let transaction = spend(&block.transactions[0].outputs[0], &key);
+        network
+            .node(1)
+            .submit_transaction(transaction, now)
+            .unwrap();
+        network.run(now);
+        for n in 0..3 {
+            assert_eq!(network.node(n).blockchain().mempool().len(), 1);
+        }
+
+        // the block went out compact, every node fetched the transaction
+        // once, and no peer was told twice
+        for n in 0..3 {
+            assert_eq!(
+                network.received(n, |m| matches!(m, Message::NewBlock(_))),
+                0
+            );
+            assert_eq!(
+                network.received(n, |m| matches!(m, Message::NewTransaction(_))),
+                usize::from(n != 1)
+            );
+        }
+        let announcements: Vec<_> = network
+            .log
+            .iter()
+            .flat_map(|(from, to, message)| match message {
+                Message::Inv(items) => items.iter().map(|item| (*from, *to, *item)).collect(),
+                Message::CompactBlock(compact) => {
+                    vec![(*from, *to, InventoryItem::Block(compact.header.hash()))]
+                }
+                _ => vec![],
+            })
+            .collect();
+        let unique: HashSet<_> = announcements.iter().collect();
+        assert_eq!(unique.len(), announcements.len());
+    }
+
+    #[test]
+    fn catches_up_on_connect() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        let mut network = Network::new(2);
+        for _ in 0..3 {
+            let block = mine(network.node(0), &key);
+            network.node(0).submit_block(block).unwrap();
+        }
+        network.connect(1, 0, now);
+        network.run(now);
+        assert_eq!(network.node(1).blockchain().block_height(), 4);
+
+        // unknown objects are answered with NotFound
+        let unknown = InventoryItem::Transaction(Hash::zero());
+        let (address, node) = (network.address(1), network.node(0));
+        node.receive(address, Message::GetData(vec![unknown]), now);
+        assert!(matches!(
+            &node.take_actions()[..],
+            [Action::Send(_, Message::NotFound(items))] if items == &[unknown]
+        ));
+    }
+
+    #[test]
+    fn samples_clock_of_outbound_peers() {
+        let now = Instant::now();
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let ahead = Utc::now() + chrono::Duration::hours(1);
+        for n in 1..=5 {
+            // inbound peers and repeated versions are not counted
+            let inbound = format!("10.0.1.{n}:9333").parse().unwrap();
+            node.connected(inbound, false, now);
+            node.receive(inbound, Message::Version(ahead), now);
+            let outbound = format!("10.0.2.{n}:9333").parse().unwrap();
+            node.connected(outbound, true, now);
+            node.receive(outbound, Message::Version(Utc::now()), now);
+            node.receive(outbound, Message::Version(ahead), now);
+        }
+        assert_eq!(
+            node.blockchain().network_time().offset(),
+            chrono::Duration::zero()
+        );
+
+        for n in 1..=5 {
+            let outbound = format!("10.0.3.{n}:9333").parse().unwrap();
+            node.connected(outbound, true, now);
+            node.receive(outbound, Message::Version(ahead), now);
+            node.receive(outbound, Message::Version(ahead), now);
+        }
+        let offset = node.blockchain().network_time().offset();
+        assert!((offset - chrono::Duration::hours(1)).num_seconds().abs() <= 1);
+    }
+
+    #[test]
+    fn serves_filters_to_light_clients() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        let mut network = Network::new(1);
+        for _ in 0..2 {
+            let block = mine(network.node(0), &key);
+            network.node(0).submit_block(block).unwrap();
+        }
+        let client: SocketAddr = "10.0.1.1:9333".parse().unwrap();
+        let node = network.node(0);
+        node.connected(client, false, now);
+        node.take_actions();
+        node.receive(client, Message::GetFilterHeaders(0), now);
+        node.receive(client, Message::GetFilters(1), now);
+
+        // through the wire format, as a light client would get them
+        let mut wire = vec![];
+        for action in node.take_actions() {
+            if let Action::Send(_, message) = action {
+                message.send(&mut wire).unwrap();
+            }
+        }
+        let mut wire = &wire[..];
+        let Message::FilterHeaders(headers) = Message::receive(&mut wire).unwrap() else {
+            panic!("expected filter headers");
+        };
+        let Message::Filters(filters) = Message::receive(&mut wire).unwrap() else {
+            panic!("expected filters");
+        };
+        assert_eq!((headers.len(), filters.len()), (3, 2));
+        for (n, (hash, filter)) in filters.iter().enumerate() {
+            assert_eq!(filter.header(&headers[n]), headers[n + 1]);
+            assert!(filter.matches_any(hash, &[key.public_key()], &[]));
+        }
+    }
+
+    #[test]
+    fn fetches_missing_transactions() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        let mut network = Network::new(2);
+        let block = mine(network.node(0), &key);
+        network.node(0).submit_block(block.clone()).unwrap();
+        network
+            .node(1)
+            .blockchain_mut()
+            .add_block(block.clone())
+            .unwrap();
+        // only node 0 has the transaction
+        let transaction = spend(&block.transactions[0].outputs[0], &key);
+        network
+            .node(0)
+            .submit_transaction(transaction, now)
+            .unwrap();
+        network.connect(0, 1, now);
+        network.run(now);
+
+        let block = mine(network.node(0), &key);
+        assert_eq!(block.transactions.len(), 2);
+        network.node(0).submit_block(block).unwrap();
+        network.run(now);
+        assert_eq!(network.node(1).blockchain().block_height(), 3);
+        assert_eq!(
+            network.received(1, |m| matches!(m, Message::BlockTransactions { .. })),
+            1
+        );
+        assert_eq!(
+            network.received(1, |m| matches!(m, Message::NewBlock(_))),
+            0
+        );
+    }
+
+    #[test]
+    fn falls_back_on_short_id_collision() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        let mut network = Network::new(2);
+        let mut outputs = vec![];
+        for _ in 0..2 {
+            let block = mine(network.node(0), &key);
+            network.node(0).submit_block(block.clone()).unwrap();
+            network
+                .node(1)
+                .blockchain_mut()
+                .add_block(block.clone())
+                .unwrap();
+            outputs.push(block.transactions[0].outputs[0].clone());
+        }
+        let mined = spend(&outputs[0], &key);
+        let other = spend(&outputs[1], &key);
+        network.node(0).submit_transaction(mined, now).unwrap();
+        network
+            .node(1)
+            .submit_transaction(other.clone(), now)
+            .unwrap();
+        network.connect(0, 1, now);
+        network.run(now);
+
+        let block = mine(network.node(0), &key);
+        network
+            .node(0)
+            .blockchain_mut()
+            .add_block(block.clone())
+            .unwrap();
+        // node 1 resolves the short id to its own transaction
+        let mut compact = CompactBlock::new(&block);
+        compact.short_ids[0] = compact.short_id(&other.hash());
+        let address = network.address(0);
+        network
+            .node(1)
+            .receive(address, Message::CompactBlock(compact), now);
+        network.run(now);
+
+        assert_eq!(network.node(1).blockchain().block_height(), 4);
+        assert_eq!(
+            network.received(1, |m| matches!(m, Message::NewBlock(_))),
+            1
+        );
+        assert_eq!(network.node(1).peer_info()[0].misbehaviour, 0);
+    }
+
+    #[test]
+    fn bans_on_bad_prefilled_index() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        let mut network = Network::new(2);
+        network.connect(0, 1, now);
+        network.run(now);
+
+        let block = mine(network.node(0), &key);
+        let mut compact = CompactBlock::new(&block);
+        compact.prefilled[0].0 = 1;
+        let address = network.address(0);
+        network
+            .node(1)
+            .receive(address, Message::CompactBlock(compact), now);
+        network.run(now);
+
+        assert_eq!(network.node(1).blockchain().block_height(), 1);
+        assert!(network.node(0).peer_info().is_empty());
+        assert!(network.node(1).peer_info().is_empty());
+
+        // and stays out
+        assert!(network.node(1).bans().is_banned(&address.ip()));
+        network.connect(0, 1, now);
+        network.run(now);
+        assert!(network.node(1).peer_info().is_empty());
+    }
+
+    #[test]
+    fn stems_then_fluffs_after_embargo() {
+        let now = Instant::now();
+        let key = PrivateKey::new_key();
+        // 0 -> 1 -> a peer that drops everything, and 2 listening to both
+        let mut network = Network::new(3);
+        for n in 0..3 {
+            network.node(n).dandelion.fluff_probability = 0.0;
+        }
+        network.connect(0, 1, now);
+        network.connect(2, 0, now);
+        network.connect(2, 1, now);
+        let blackhole: SocketAddr = "10.0.9.9:9333".parse().unwrap();
+        network.node(1).connected(blackhole, true, now);
+        // and a spy on both stem nodes, recorded in the log only
+        let spy: SocketAddr = "10.0.9.8:9333".parse().unwrap();
+        for n in 0..2 {
+            network.node(n).connected(spy, false, now);
+        }
+        network.run(now);
+
+        let block = mine(network.node(0), &key);
+        network.node(0).submit_block(block.clone()).unwrap();
+        network.run(now);
+        let transaction = spend(&block.transactions[0].outputs[0], &key);
+        let hash = transaction.hash();
+        network
+            .node(0)
+            .submit_transaction(transaction, now)
+            .unwrap();
+        network.run(now);
+
+        // the stem went 0 -> 1 -> blackhole, nothing was announced
+        assert_eq!(
+            network.received(1, |m| matches!(m, Message::StemTransaction(_))),
+            1
+        );
+        // it waits in the stempool, so the mempool, templates and UTXO
+        // answers all look as if it never arrived
+        for n in 0..3 {
+            assert!(network.node(n).blockchain().mempool().is_empty());
+        }
+        assert_eq!(
+            network.received(2, |m| matches!(
+                m,
+                Message::Inv(_) | Message::NewTransaction(_)
+            )),
+            0
+        );
+        // claiming to know it and then asking for it gives nothing away
+        // either: both nodes want it from the spy and have nothing to send
+        let item = InventoryItem::Transaction(hash);
+        for n in 0..2 {
+            network.node(n).receive(spy, Message::Inv(vec![item]), now);
+            network
+                .node(n)
+                .receive(spy, Message::GetData(vec![item]), now);
+        }
+        network.run(now);
+        let to_spy = |filter: fn(&Message) -> bool| {
+            network
+                .log
+                .iter()
+                .filter(|(_, to, message)| *to == spy && filter(message))
+                .count()
+        };
+        assert_eq!(to_spy(|m| matches!(m, Message::GetData(_))), 2);
+        assert_eq!(to_spy(|m| matches!(m, Message::NotFound(_))), 2);
+        assert_eq!(to_spy(|m| matches!(m, Message::NewTransaction(_))), 0);
+
+        // the stem never came back fluffed, so node 1 announces it itself
+        let later = now + EMBARGO + EMBARGO_JITTER + std::time::Duration::from_secs(1);
+        network.node(1).tick(later);
+        network.run(later);
+        assert_eq!(network.node(1).blockchain().mempool().len(), 1);
+        assert_eq!(network.node(2).blockchain().mempool().len(), 1);
+        assert_eq!(
+            network.received(2, |m| matches!(m, Message::NewTransaction(_))),
+            1
+        );
+    }
+
+    #[test]
+    fn pings_and_drops_silent_peers() {
+        let start = Instant::now();
+        let mut network = Network::new(2);
+        network.connect(0, 1, start);
+        network.run(start);
+
+        let later = start + PING_INTERVAL;
+        network.node(0).tick(later);
+        network.run(later);
+        assert_eq!(network.received(1, |m| matches!(m, Message::Ping(_))), 1);
+        assert!(network.node(0).peer_info()[0].rtt.is_some());
+
+        // node 1 goes quiet: its pong never arrives
+        let later = later + PING_INTERVAL;
+        network.node(0).tick(later);
+        network.node(0).take_actions();
+        network
+            .node(0)
+            .tick(later + PING_TIMEOUT + std::time::Duration::from_secs(1));
+        assert!(network.node(0).peer_info().is_empty());
+        let silent = network.address(1);
+        assert!(matches!(
+            &network.node(0).take_actions()[..],
+            [Action::Disconnect(address)] if *address == silent
+        ));
+    }
+}
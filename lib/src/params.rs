@@ -0,0 +1,363 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::amount::Amount;
+use crate::crypto::PublicKey;
+use crate::difficulty::{DifficultyAdjustment, DifficultyAlgorithm, Lwma, Windowed};
+use crate::sha256::Hash;
+use crate::target::CompactTarget;
+use crate::types::{Block, BlockHeader, Transaction, TransactionOutput};
+use crate::util::{MerkleRoot, Saveable};
+use crate::U256;
+
+// nobody holds the key, the genesis coinbase can never be spent
+const GENESIS_PUBKEY: &str = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+/// Consensus and policy parameters of a network
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChainParams {
+    /// preset the parameters are based on, custom networks pick the closest one
+    pub network: Network,
+    /// coinbase reward of the first blocks, in whole coins
+    pub initial_reward: u64,
+    /// blocks
+    pub halving_interval: u64,
+    /// easiest target a block may have
+    pub max_target: CompactTarget,
+    pub difficulty: DifficultyAdjustment,
+    /// max mempool trx age (seconds)
+    pub max_mempool_transaction_age: u64,
+    pub genesis_timestamp: DateTime<Utc>,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            network: Network::Mainnet,
+            initial_reward: 50,
+            halving_interval: 210,
+            max_target: CompactTarget::from_target(crate::MIN_TARGET),
+            difficulty: DifficultyAdjustment::Windowed(Windowed {
+                interval: 50,
+                block_time: 10,
+                max_factor: 4,
+            }),
+            max_mempool_transaction_age: 600,
+            genesis_timestamp: DateTime::from_timestamp(1_733_011_200, 0).unwrap(),
+        }
+    }
+
+    pub fn testnet() -> Self {
+        ChainParams {
+            network: Network::Testnet,
+            max_target: CompactTarget::from_target(crate::MIN_TARGET << 4),
+            difficulty: DifficultyAdjustment::Lwma(Lwma {
+                window: 45,
+                block_time: 10,
+            }),
+            genesis_timestamp: DateTime::from_timestamp(1_733_097_600, 0).unwrap(),
+            ..Self::mainnet()
+        }
+    }
+
+    /// Trivial proof of work and a fixed target, for local tests
+    pub fn regtest() -> Self {
+        ChainParams {
+            network: Network::Regtest,
+            halving_interval: 150,
+            max_target: CompactTarget(0x207f_ffff),
+            difficulty: DifficultyAdjustment::Fixed { block_time: 1 },
+            max_mempool_transaction_age: 60,
+            ..Self::mainnet()
+        }
+    }
+
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Mainnet => Self::mainnet(),
+            Network::Testnet => Self::testnet(),
+            Network::Regtest => Self::regtest(),
+        }
+    }
+
+    /// `arg` is either a network name or the path of a params file
+    pub fn from_arg(arg: &str) -> IoResult<Self> {
+        match arg.parse() {
+            Ok(network) => Ok(Self::for_network(network)),
+            Err(_) => Self::load_from_file(arg),
+        }
+    }
+
+    /// The fixed first block of the network. It is trusted as is: its
+    /// proof of work is not checked and its coinbase is unspendable.
+    pub fn genesis_block(&self) -> Block {
+        let pubkey = hex::decode(GENESIS_PUBKEY)
+            .ok()
+            .and_then(|bytes| ecdsa::VerifyingKey::from_sec1_bytes(&bytes).ok())
+            .expect("genesis_block: invalid genesis public key");
+
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: self.block_reward(0),
+                unique_id: Uuid::nil(),
+                pubkey: PublicKey::Ecdsa(pubkey),
+            }],
+        )];
+        let merkle_root = MerkleRoot::calculate(&transactions).expect("genesis has a coinbase");
+
+        Block::new(
+            BlockHeader::new(
+                self.genesis_timestamp,
+                0,
+                Hash::zero(),
+                merkle_root,
+                self.max_target(),
+            ),
+            transactions,
+        )
+    }
+
+    /// Reject values the accessors below would panic on
+    pub fn validate(&self) -> Result<(), String> {
+        if self.halving_interval == 0 {
+            return Err("halving_interval must not be zero".to_string());
+        }
+        match self.max_target.to_target() {
+            Some(target) if !target.is_zero() => {}
+            _ => {
+                return Err(format!(
+                    "max_target {} is not a valid target",
+                    self.max_target
+                ))
+            }
+        }
+        if !Amount::from_btc(self.initial_reward).is_some_and(Amount::is_valid) {
+            return Err(format!(
+                "initial_reward {} exceeds MAX_MONEY",
+                self.initial_reward
+            ));
+        }
+        self.difficulty
+            .validate()
+            .map_err(|e| format!("difficulty: {e}"))
+    }
+
+    pub fn max_target(&self) -> U256 {
+        self.max_target
+            .to_target()
+            .expect("max_target: invalid compact target in chain params")
+    }
+
+    /// seconds
+    pub fn block_time(&self) -> u64 {
+        self.difficulty.block_time()
+    }
+
+    /// Newly minted coins in the block at `height`
+    pub fn block_reward(&self, height: u64) -> Amount {
+        let halvings = height / self.halving_interval;
+        let initial_reward = Amount::from_btc(self.initial_reward)
+            .filter(|reward| reward.is_valid())
+            .expect("block_reward: initial reward exceeds MAX_MONEY");
+        Amount::from_sat(
+            initial_reward
+                .to_sat()
+                .checked_shr(halvings as u32)
+                .unwrap_or(0),
+        )
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+// custom networks are written by hand, so save and load as TOML
+impl Saveable for ChainParams {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf)?;
+
+        let params: ChainParams = toml::from_str(&buf).map_err(|e| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                format!("Failed to parse ChainParams: {e}"),
+            )
+        })?;
+        // bad values would only panic once the chain uses them
+        params.validate().map_err(|e| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                format!("Invalid ChainParams: {e}"),
+            )
+        })?;
+        Ok(params)
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        let s = toml::to_string(self).map_err(|_| {
+            IoError::new(IoErrorKind::InvalidData, "Failed to serialize ChainParams")
+        })?;
+        writer.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_round_trip_through_toml() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            let params = ChainParams::for_network(network);
+            let mut buf = vec![];
+            params.save(&mut buf).unwrap();
+            assert_eq!(ChainParams::load(buf.as_slice()).unwrap(), params);
+        }
+    }
+
+    #[test]
+    fn block_reward_halves() {
+        let params = ChainParams::mainnet();
+        assert_eq!(params.block_reward(0), Amount::from_btc(50).unwrap());
+        assert_eq!(params.block_reward(210), Amount::from_btc(25).unwrap());
+        assert_eq!(params.block_reward(210 * 64), Amount::ZERO);
+    }
+
+    #[test]
+    fn custom_network_from_toml() {
+        let config = r#"
+            network = "regtest"
+            initial_reward = 10
+            halving_interval = 1000
+            max_target = 545259519
+            max_mempool_transaction_age = 30
+            genesis_timestamp = "2025-01-01T00:00:00Z"
+
+            [difficulty]
+            algorithm = "asert"
+            half_life = 600
+            block_time = 2
+        "#;
+        let params = ChainParams::load(config.as_bytes()).unwrap();
+
+        assert_eq!(params.block_time(), 2);
+        assert_eq!(params.max_target(), ChainParams::regtest().max_target());
+        assert_eq!(params.block_reward(1000), Amount::from_btc(5).unwrap());
+        assert_ne!(
+            params.genesis_block().hash(),
+            ChainParams::regtest().genesis_block().hash()
+        );
+    }
+
+    // a valid custom network with one line replaced
+    fn load_with(field: &str, value: &str) -> IoResult<ChainParams> {
+        let config = r#"
+            network = "regtest"
+            initial_reward = 10
+            halving_interval = 1000
+            max_target = 545259519
+            max_mempool_transaction_age = 30
+            genesis_timestamp = "2025-01-01T00:00:00Z"
+
+            [difficulty]
+            algorithm = "lwma"
+            window = 45
+            block_time = 2
+        "#;
+        let prefix = format!("{field} =");
+        let config: String = config
+            .lines()
+            .map(|line| {
+                if line.trim().starts_with(&prefix) {
+                    format!("{field} = {value}\n")
+                } else {
+                    format!("{line}\n")
+                }
+            })
+            .collect();
+        ChainParams::load(config.as_bytes())
+    }
+
+    // parses, but fails validation
+    fn rejected(field: &str, value: &str) -> bool {
+        load_with(field, value).is_err_and(|e| {
+            e.kind() == IoErrorKind::InvalidData && e.to_string().starts_with("Invalid ChainParams")
+        })
+    }
+
+    #[test]
+    fn rejects_zero_difficulty_parameters() {
+        assert!(load_with("window", "45").is_ok());
+        assert!(rejected("window", "0"));
+    }
+
+    #[test]
+    fn rejects_zero_halving_interval() {
+        assert!(rejected("halving_interval", "0"));
+    }
+
+    #[test]
+    fn rejects_invalid_max_target() {
+        // negative, overflowing and zero encodings
+        for bits in ["0x04800001", "0xff7fffff", "0"] {
+            assert!(rejected("max_target", bits), "{bits}");
+        }
+    }
+
+    #[test]
+    fn rejects_initial_reward_above_max_money() {
+        assert!(load_with("initial_reward", "21000000").is_ok());
+        assert!(rejected("initial_reward", "21000001"));
+    }
+
+    #[test]
+    fn genesis_is_deterministic() {
+        let params = ChainParams::mainnet();
+        assert_eq!(params.genesis_block().hash(), params.genesis_block().hash());
+        assert_ne!(
+            params.genesis_block().hash(),
+            ChainParams::testnet().genesis_block().hash()
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Per-peer bookkeeping for nodes.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::net::IpAddr;
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::BtcError;
+use crate::sha256::Hash;
+use crate::util::Saveable;
+
+/// Misbehaviour score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a misbehaving peer stays banned by default (seconds)
+pub const DEFAULT_BAN_DURATION: i64 = 24 * 60 * 60;
+
+/// An object announced by hash through `Inv` and requested with `GetData`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum InventoryItem {
+    Transaction(Hash),
+    Block(Hash),
+}
+
+/// What a peer is known to have, because it announced or sent it or we
+/// sent it to it. Nothing known is ever announced to the peer again.
+#[derive(Clone, Debug, Default)]
+pub struct KnownInventory {
+    items: HashSet<InventoryItem>,
+    // insertion order, oldest first, to forget when full
+    order: VecDeque<InventoryItem>,
+}
+
+impl KnownInventory {
+    // bounds memory per peer; forgetting only risks a redundant announcement
+    const MAX_ITEMS: usize = 50_000;
+
+    pub fn new() -> Self {
+        KnownInventory::default()
+    }
+
+    pub fn contains(&self, item: &InventoryItem) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn insert(&mut self, item: InventoryItem) {
+        if !self.items.insert(item) {
+            return;
+        }
+        self.order.push_back(item);
+        if self.order.len() > Self::MAX_ITEMS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+    }
+
+    /// The items to announce to this peer, which are marked known
+    pub fn unknown(&mut self, items: &[InventoryItem]) -> Vec<InventoryItem> {
+        let mut unknown = vec![];
+        for item in items {
+            if !self.contains(item) {
+                self.insert(*item);
+                unknown.push(*item);
+            }
+        }
+        unknown
+    }
+}
+
+/// Ping a quiet peer after this long
+pub const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2 * 60);
+/// Disconnect a peer that has not answered a ping within this long
+pub const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20 * 60);
+
+/// What the connection loop should do next, see `Keepalive::poll`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeepaliveAction {
+    Wait,
+    SendPing(u64),
+    Disconnect,
+}
+
+/// Liveness and round-trip time of one connection
+#[derive(Clone, Debug)]
+pub struct Keepalive {
+    last_received: Instant,
+    // nonce and send time of the unanswered ping
+    pending: Option<(u64, Instant)>,
+    rtt: Option<std::time::Duration>,
+}
+
+impl Keepalive {
+    pub fn new(now: Instant) -> Self {
+        Keepalive {
+            last_received: now,
+            pending: None,
+            rtt: None,
+        }
+    }
+
+    /// Any message shows the peer is alive
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    pub fn poll(&mut self, now: Instant) -> KeepaliveAction {
+        match self.pending {
+            Some((_, sent)) if now.duration_since(sent) > PING_TIMEOUT => {
+                KeepaliveAction::Disconnect
+            }
+            Some(_) => KeepaliveAction::Wait,
+            None if now.duration_since(self.last_received) >= PING_INTERVAL => {
+                let nonce = rand::random();
+                self.pending = Some((nonce, now));
+                KeepaliveAction::SendPing(nonce)
+            }
+            None => KeepaliveAction::Wait,
+        }
+    }
+
+    /// Record a `Pong`, false if it does not answer our ping
+    pub fn pong(&mut self, nonce: u64, now: Instant) -> bool {
+        match self.pending {
+            Some((expected, sent)) if expected == nonce => {
+                self.pending = None;
+                self.rtt = Some(now.duration_since(sent));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Last measured round-trip time
+    pub fn rtt(&self) -> Option<std::time::Duration> {
+        self.rtt
+    }
+}
+
+/// A connected peer as shown in the node's peer listing
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PeerInfo {
+    pub address: String,
+    /// The peer opened the connection
+    pub inbound: bool,
+    /// Last ping round-trip time, None before the first pong
+    pub rtt: Option<std::time::Duration>,
+    pub misbehaviour: u32,
+}
+
+/// Something a peer did wrong
+#[derive(Debug)]
+pub enum Misbehaviour<'a> {
+    /// Sent a block or transaction that failed validation
+    Invalid(&'a BtcError),
+    /// Sent bytes that do not decode as a `Message`
+    MalformedMessage,
+    /// Sent something nobody asked for, or too much of it
+    Spam,
+}
+
+impl Misbehaviour<'_> {
+    /// Points towards `BAN_THRESHOLD`. Failures an honest peer can cause,
+    /// by being on another tip or a racing mempool, cost nothing.
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehaviour::Invalid(error) => match error {
+                // honest peers hit these through forks, races and clock skew
+                BtcError::MissingInput { .. }
+                | BtcError::PrevBlockMismatch { .. }
+                | BtcError::TimestampTooNew { .. } => 0,
+                BtcError::InvalidAmount
+                | BtcError::InvalidHash
+                | BtcError::InvalidPublicKey
+                | BtcError::InvalidPrivateKey
+                | BtcError::InvalidTransactionInput
+                | BtcError::InvalidTransactionOutput => 10,
+                BtcError::InvalidTransaction
+                | BtcError::InvalidBlock
+                | BtcError::InvalidBlockHeader
+                | BtcError::InvalidMerkleRoot
+                | BtcError::InvalidSignature
+                | BtcError::EmptyMerkleTree
+                | BtcError::MutatedMerkleTree
+                | BtcError::TransactionTooLarge { .. }
+                | BtcError::BadSignature { .. }
+                // spending an output twice within one transaction or block,
+                // unlike a mempool conflict, is never a race
+                | BtcError::DuplicateInput { .. }
+                | BtcError::DuplicateOutput { .. }
+                | BtcError::ValueOutOfRange { .. }
+                | BtcError::Overspend { .. }
+                | BtcError::MalformedCoinbase { .. }
+                | BtcError::CoinbaseMismatch { .. }
+                | BtcError::BlockTooLarge { .. }
+                | BtcError::EmptyBlock { .. }
+                | BtcError::DuplicateTransaction { .. }
+                | BtcError::BadMerkleRoot { .. }
+                | BtcError::BadPrefilledIndex { .. }
+                | BtcError::WrongTarget { .. }
+                | BtcError::InsufficientProofOfWork { .. }
+                | BtcError::TimestampTooOld { .. }
+                | BtcError::BadGenesis { .. } => BAN_THRESHOLD,
+            },
+            Misbehaviour::MalformedMessage => 20,
+            Misbehaviour::Spam => 5,
+        }
+    }
+}
+
+/// Accumulated misbehaviour of a connected peer
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerScore(u32);
+
+impl PeerScore {
+    pub fn score(&self) -> u32 {
+        self.0
+    }
+
+    /// Record `misbehaviour`, true once the peer should be banned
+    pub fn punish(&mut self, misbehaviour: Misbehaviour) -> bool {
+        self.0 = self.0.saturating_add(misbehaviour.score());
+        self.0 >= BAN_THRESHOLD
+    }
+}
+
+/// Banned addresses and when each ban ends
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BanList {
+    bans: BTreeMap<IpAddr, DateTime<Utc>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        let until = Utc::now() + duration;
+        let ban = self.bans.entry(ip).or_insert(until);
+        *ban = (*ban).max(until);
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.get(ip).is_some_and(|until| *until > Utc::now())
+    }
+
+    /// True if `ip` was banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.bans.clear();
+    }
+
+    /// Active bans, expired ones are dropped
+    pub fn list(&mut self) -> Vec<(IpAddr, DateTime<Utc>)> {
+        let now = Utc::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans.iter().map(|(ip, until)| (*ip, *until)).collect()
+    }
+}
+
+impl Saveable for BanList {
+    fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf)?;
+
+        toml::from_str(&buf).map_err(|e| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                format!("Failed to parse BanList: {e}"),
+            )
+        })
+    }
+
+    fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+        let s = toml::to_string(self)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize BanList"))?;
+        writer.write_all(s.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_after_invalid_block() {
+        let mut score = PeerScore::default();
+        let fork = BtcError::PrevBlockMismatch {
+            block: Hash::zero(),
+            expected: Hash::zero(),
+            found: Hash::zero(),
+        };
+        assert!(!score.punish(Misbehaviour::Invalid(&fork)));
+        assert!(!score.punish(Misbehaviour::MalformedMessage));
+        assert!(score.punish(Misbehaviour::Invalid(&BtcError::InvalidBlock)));
+
+        let double_spend = BtcError::DuplicateInput {
+            tx: Hash::zero(),
+            input: 1,
+            output: Hash::zero(),
+        };
+        assert!(PeerScore::default().punish(Misbehaviour::Invalid(&double_spend)));
+    }
+
+    #[test]
+    fn keepalive() {
+        let start = Instant::now();
+        let mut keepalive = Keepalive::new(start);
+        assert_eq!(keepalive.poll(start), KeepaliveAction::Wait);
+
+        let later = start + PING_INTERVAL;
+        let KeepaliveAction::SendPing(nonce) = keepalive.poll(later) else {
+            panic!("expected a ping");
+        };
+        assert!(!keepalive.pong(nonce.wrapping_add(1), later));
+        let answered = later + std::time::Duration::from_millis(40);
+        assert!(keepalive.pong(nonce, answered));
+        assert_eq!(keepalive.rtt(), Some(std::time::Duration::from_millis(40)));
+
+        keepalive.received(answered);
+        assert!(matches!(
+            keepalive.poll(answered + PING_INTERVAL),
+            KeepaliveAction::SendPing(_)
+        ));
+        assert_eq!(
+            keepalive.poll(answered + PING_INTERVAL + PING_TIMEOUT * 2),
+            KeepaliveAction::Disconnect
+        );
+    }
+
+    #[test]
+    fn ban_list() {
+        let banned: IpAddr = "192.0.2.1".parse().unwrap();
+        let expired: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut bans = BanList::new();
+        bans.ban(banned, Duration::seconds(DEFAULT_BAN_DURATION));
+        bans.ban(expired, Duration::seconds(-1));
+        assert!(bans.is_banned(&banned));
+        assert!(!bans.is_banned(&expired));
+
+        let mut saved = vec![];
+        bans.save(&mut saved).unwrap();
+        let mut loaded = BanList::load(&saved[..]).unwrap();
+        assert_eq!(loaded, bans);
+
+        assert_eq!(loaded.list().len(), 1);
+        assert!(loaded.unban(&banned));
+        assert!(loaded.list().is_empty());
+    }
+
+    #[test]
+    fn announces_each_item_once() {
+        let block = InventoryItem::Block(Hash::hash_bytes(b"block"));
+        let tx = InventoryItem::Transaction(Hash::hash_bytes(b"tx"));
+        let mut known = KnownInventory::new();
+        known.insert(tx);
+        assert_eq!(known.unknown(&[block, tx]), vec![block]);
+        assert!(known.unknown(&[block, tx]).is_empty());
+    }
+}
@@ -0,0 +1,664 @@
+//! JSON-RPC over HTTP, modelled on bitcoind's: POST a
+//! `{"method", "params", "id"}` object (or an array of them) with basic
+//! auth, get `{"result", "error", "id"}` back. Hashes are big-endian hex,
+//! blocks and transactions travel hex encoded in their consensus encoding,
+//! values are in satoshis.
+
+use std::fs;
+use std::io::{Result as IoResult, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Instant;
+
+use chrono::Duration;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::crypto::PublicKey;
+use crate::encode::{Decodable, Encodable};
+use crate::http::{Request, Response};
+use crate::node::Node;
+use crate::peer::DEFAULT_BAN_DURATION;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Blockchain, Transaction, TransactionOutput};
+use crate::util::MerkleRoot;
+use crate::MAX_BLOCK_SIZE;
+
+pub const COOKIE_FILE: &str = ".cookie";
+const COOKIE_USER: &str = "__cookie__";
+
+// bitcoind's error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INVALID_ADDRESS_OR_KEY: i64 = -5;
+pub const DESERIALIZATION_ERROR: i64 = -22;
+pub const VERIFY_ERROR: i64 = -25;
+pub const CLIENT_INVALID_IP_OR_SUBNET: i64 = -30;
+
+// room left in a template for the coinbase
+const COINBASE_RESERVE: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Credentials a client has to present with basic auth
+#[derive(Clone, Debug)]
+pub struct RpcAuth {
+    pub user: String,
+    password: String,
+}
+
+impl RpcAuth {
+    pub fn new(user: &str, password: &str) -> Self {
+        RpcAuth {
+            user: user.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// Random credentials written to `dir/.cookie` as `__cookie__:password`,
+    /// so that local tools with access to the data directory can log in.
+    /// A new cookie is written on every start, readable by the owner only;
+    /// `remove_cookie` deletes it on shutdown.
+    pub fn cookie(dir: &Path) -> IoResult<Self> {
+        let password = hex::encode(rand::random::<[u8; 32]>());
+        let path = dir.join(COOKIE_FILE);
+        // a stale cookie may have other permissions, which opening would keep
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)?
+            .write_all(format!("{COOKIE_USER}:{password}").as_bytes())?;
+        Ok(RpcAuth {
+            user: COOKIE_USER.to_string(),
+            password,
+        })
+    }
+
+    pub fn remove_cookie(dir: &Path) -> IoResult<()> {
+        fs::remove_file(dir.join(COOKIE_FILE))
+    }
+
+    /// Read the credentials of a running node from its cookie file
+    pub fn read_cookie(dir: &Path) -> IoResult<(String, String)> {
+        let cookie = fs::read_to_string(dir.join(COOKIE_FILE))?;
+        let (user, password) = cookie.trim().split_once(':').ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "malformed cookie file")
+        })?;
+        Ok((user.to_string(), password.to_string()))
+    }
+
+    pub fn check(&self, request: &Request) -> bool {
+        match request.basic_auth() {
+            Some((user, password)) => {
+                // compare everything so timing does not reveal the prefix
+                (user == self.user)
+                    & constant_time_eq(password.as_bytes(), self.password.as_bytes())
+            }
+            None => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Answer an HTTP request to the RPC server
+pub fn respond(auth: &RpcAuth, node: &mut Node, request: &Request) -> Response {
+    if !auth.check(request) {
+        return Response::text(401, "unauthorized");
+    }
+    if request.method != "POST" {
+        return Response::text(405, "JSON-RPC server handles only POST requests");
+    }
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, format!("parse error: {e}"));
+            return Response::json(500, &reply(Value::Null, Err(error)));
+        }
+    };
+    match body {
+        Value::Array(calls) => {
+            let replies = calls.iter().map(|call| handle(node, call)).collect();
+            Response::json(200, &Value::Array(replies))
+        }
+        call => {
+            let reply = handle(node, &call);
+            // like bitcoind, a single failed call is also an HTTP error
+            let status = match reply["error"] {
+                Value::Null => 200,
+                _ => 500,
+            };
+            Response::json(status, &reply)
+        }
+    }
+}
+
+/// Execute one call, always giving a reply object
+pub fn handle(node: &mut Node, call: &Value) -> Value {
+    let id = call.get("id").cloned().unwrap_or(Value::Null);
+    let Some(method) = call.get("method").and_then(Value::as_str) else {
+        return reply(id, Err(RpcError::new(INVALID_REQUEST, "missing method")));
+    };
+    let params = match call.get("params") {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => {
+            return reply(
+                id,
+                Err(RpcError::new(INVALID_REQUEST, "params must be an array")),
+            )
+        }
+    };
+    reply(id, dispatch(node, method, &params))
+}
+
+fn reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null, "id": id }),
+        Err(error) => json!({
+            "result": null,
+            "error": { "code": error.code, "message": error.message },
+            "id": id,
+        }),
+    }
+}
+
+fn dispatch(node: &mut Node, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+    let blockchain = node.blockchain();
+    match method {
+        "getblockcount" => Ok(json!(blockchain.block_height() - 1)),
+        "getbestblockhash" => {
+            let tip = blockchain
+                .blocks()
+                .last()
+                .expect("chain has a genesis block");
+            Ok(json!(hex_hash(&tip.hash())))
+        }
+        "getblockhash" => {
+            let height = param_u64(params, 0, "height")?;
+            blockchain
+                .blocks()
+                .nth(height as usize)
+                .map(|block| json!(hex_hash(&block.hash())))
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "block height out of range"))
+        }
+        "getblock" => {
+            let hash = param_hash(params, 0, "blockhash")?;
+            let verbosity = match params.get(1) {
+                None | Some(Value::Null) => 1,
+                Some(Value::Bool(verbose)) => *verbose as u64,
+                Some(_) => param_u64(params, 1, "verbosity")?,
+            };
+            let (height, block) = blockchain
+                .blocks()
+                .enumerate()
+                .find(|(_, block)| block.hash() == hash)
+                .ok_or_else(|| RpcError::new(INVALID_ADDRESS_OR_KEY, "block not found"))?;
+            match verbosity {
+                0 => Ok(json!(hex::encode(block.consensus_bytes()))),
+                _ => Ok(block_json(blockchain, height as u64, block, verbosity > 1)),
+            }
+        }
+        "getrawtransaction" => {
+            let txid = param_hash(params, 0, "txid")?;
+            let verbose = params.get(1).is_some_and(|verbose| match verbose {
+                Value::Bool(verbose) => *verbose,
+                verbose => verbose.as_u64().is_some_and(|verbose| verbose > 0),
+            });
+            let confirmed = blockchain.blocks().enumerate().find_map(|(height, block)| {
+                block
+                    .transactions
+                    .iter()
+                    .find(|tx| tx.hash() == txid)
+                    .map(|tx| (Some((height as u64, block.hash())), tx))
+            });
+            let (location, transaction) = confirmed
+                .or_else(|| {
+                    blockchain
+                        .mempool()
+                        .iter()
+                        .find(|(_, tx)| tx.hash() == txid)
+                        .map(|(_, tx)| (None, tx))
+                })
+                .ok_or_else(|| {
+                    RpcError::new(
+                        INVALID_ADDRESS_OR_KEY,
+                        "no such mempool or blockchain transaction",
+                    )
+                })?;
+            if !verbose {
+                return Ok(json!(hex::encode(transaction.consensus_bytes())));
+            }
+            let mut result = transaction_json(transaction);
+            if let Some((height, block)) = location {
+                result["blockhash"] = json!(hex_hash(&block));
+                result["confirmations"] = json!(blockchain.block_height() - height);
+            }
+            Ok(result)
+        }
+        "sendrawtransaction" => {
+            let transaction: Transaction = param_decode(params, 0, "hexstring")?;
+            let txid = transaction.hash();
+            node.submit_transaction(transaction, Instant::now())
+                .map_err(|e| RpcError::new(VERIFY_ERROR, e))?;
+            Ok(json!(hex_hash(&txid)))
+        }
+        "getmempoolinfo" => {
+            let mempool = blockchain.mempool();
+            let bytes: usize = mempool.iter().map(|(_, tx)| tx.consensus_size()).sum();
+            Ok(json!({ "size": mempool.len(), "bytes": bytes }))
+        }
+        "getblocktemplate" => {
+            let pubkey: PublicKey = param_decode(params, 0, "pubkey")?;
+            let template =
+                block_template(blockchain, pubkey).map_err(|e| RpcError::new(VERIFY_ERROR, e))?;
+            let coinbase = &template.transactions[0];
+            Ok(json!({
+                "height": blockchain.block_height(),
+                "previousblockhash": hex_hash(&template.header.prev_block_hash),
+                "bits": template.header.bits.to_string(),
+                "target": format!("{:064x}", blockchain.target()),
+                "curtime": template.header.timestamp.timestamp(),
+                "coinbasevalue": coinbase.output_value().map(|value| value.to_sat()),
+                "transactions": template
+                    .transactions
+                    .iter()
+                    .skip(1)
+                    .map(|tx| hex_hash(&tx.hash()))
+                    .collect::<Vec<_>>(),
+                "block": hex::encode(template.consensus_bytes()),
+            }))
+        }
+        "submitblock" => {
+            let block: Block = param_decode(params, 0, "hexdata")?;
+            node.submit_block(block)
+                .map_err(|e| RpcError::new(VERIFY_ERROR, e))?;
+            Ok(Value::Null)
+        }
+        "getpeerinfo" => Ok(node
+            .peer_info()
+            .iter()
+            .map(|peer| {
+                json!({
+                    "addr": peer.address,
+                    "inbound": peer.inbound,
+                    "pingtime": peer.rtt.map(|rtt| rtt.as_secs_f64()),
+                    "banscore": peer.misbehaviour,
+                })
+            })
+            .collect()),
+        "listbanned" => Ok(node
+            .bans_mut()
+            .list()
+            .iter()
+            .map(|(ip, until)| json!({ "address": ip.to_string(), "banned_until": until.timestamp() }))
+            .collect()),
+        "setban" => {
+            let ip: IpAddr = param_str(params, 0, "subnet")?
+                .parse()
+                .map_err(|_| RpcError::new(CLIENT_INVALID_IP_OR_SUBNET, "invalid IP address"))?;
+            match param_str(params, 1, "command")? {
+                "add" => {
+                    let seconds = match params.get(2) {
+                        None | Some(Value::Null) => DEFAULT_BAN_DURATION as u64,
+                        Some(_) => param_u64(params, 2, "bantime")?,
+                    };
+                    // keeps the end of the ban representable
+                    let seconds = seconds.min(i32::MAX as u64) as i64;
+                    node.ban(ip, Duration::seconds(seconds));
+                }
+                "remove" => {
+                    if !node.bans_mut().unban(&ip) {
+                        return Err(RpcError::new(
+                            CLIENT_INVALID_IP_OR_SUBNET,
+                            "IP address was not banned",
+                        ));
+                    }
+                }
+                _ => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "command must be add or remove",
+                    ))
+                }
+            }
+            Ok(Value::Null)
+        }
+        "clearbanned" => {
+            node.bans_mut().clear();
+            Ok(Value::Null)
+        }
+        "getdifficulty" => Ok(json!(blockchain.difficulty())),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+    }
+}
+
+/// A block ready to mine on top of the tip: mempool transactions by fee,
+/// as many as fit, and a coinbase paying reward and fees to `pubkey`
+pub fn block_template(blockchain: &Blockchain, pubkey: PublicKey) -> crate::error::Result<Block> {
+    // the mempool is sorted by ascending fee
+    let mut size = COINBASE_RESERVE;
+    let mut transactions = vec![];
+    for (_, transaction) in blockchain.mempool().iter().rev() {
+        let tx_size = transaction.consensus_size();
+        if size + tx_size > MAX_BLOCK_SIZE {
+            continue;
+        }
+        size += tx_size;
+        transactions.push(transaction.clone());
+    }
+
+    let prev_block_hash = blockchain
+        .blocks()
+        .last()
+        .map(Block::hash)
+        .unwrap_or_else(Hash::zero);
+    let header = BlockHeader::new(
+        blockchain.next_block_timestamp(),
+        0,
+        prev_block_hash,
+        MerkleRoot(Hash::zero()),
+        blockchain.target(),
+    );
+    let coinbase = |value| {
+        Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value,
+                unique_id: Uuid::new_v4(),
+                pubkey: pubkey.clone(),
+            }],
+        )
+    };
+
+    let reward = blockchain.params().block_reward(blockchain.block_height());
+    transactions.insert(0, coinbase(reward));
+    let mut block = Block::new(header, transactions);
+    let fees = block.calculate_miner_fees(blockchain.utxos())?;
+    let tx = block.transactions[0].hash();
+    block.transactions[0] = coinbase(
+        reward
+            .checked_add(fees)
+            .ok_or(crate::error::BtcError::ValueOutOfRange { tx })?,
+    );
+    block.header.merkle_root = MerkleRoot::calculate(&block.transactions)?;
+    Ok(block)
+}
+
+/// Hash as shown to users: 64 hex digits, big-endian
+pub fn hex_hash(hash: &Hash) -> String {
+    format!("{hash:064x}")
+}
+
+pub fn block_json(blockchain: &Blockchain, height: u64, block: &Block, full: bool) -> Value {
+    let transactions: Vec<Value> = block
+        .transactions
+        .iter()
+        .map(|tx| match full {
+            true => transaction_json(tx),
+            false => json!(hex_hash(&tx.hash())),
+        })
+        .collect();
+    json!({
+        "hash": hex_hash(&block.hash()),
+        "height": height,
+        "confirmations": blockchain.block_height() - height,
+        "time": block.header.timestamp.timestamp(),
+        "nonce": block.header.nonce,
+        "bits": block.header.bits.to_string(),
+        "difficulty": block.header.difficulty(),
+        "previousblockhash": hex_hash(&block.header.prev_block_hash),
+        "merkleroot": hex_hash(&block.header.merkle_root.0),
+        "size": block.consensus_size(),
+        "tx": transactions,
+    })
+}
+
+pub fn transaction_json(transaction: &Transaction) -> Value {
+    json!({
+        "txid": hex_hash(&transaction.hash()),
+        "size": transaction.consensus_size(),
+        "vin": transaction
+            .inputs
+            .iter()
+            .map(|input| json!({
+                "prevout": hex_hash(&input.prev_transaction_output_hash),
+                "signature": hex::encode(input.signature.consensus_bytes()),
+            }))
+            .collect::<Vec<_>>(),
+        "vout": transaction
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(n, output)| json!({
+                "n": n,
+                "hash": hex_hash(&output.hash()),
+                "value": output.value.to_sat(),
+                "unique_id": output.unique_id.to_string(),
+                "pubkey": hex::encode(output.pubkey.consensus_bytes()),
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn param_u64(params: &[Value], index: usize, name: &str) -> Result<u64, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_u64)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{name} must be a number")))
+}
+
+fn param_str<'a>(params: &'a [Value], index: usize, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("{name} must be a string")))
+}
+
+fn param_hash(params: &[Value], index: usize, name: &str) -> Result<Hash, RpcError> {
+    param_str(params, index, name)?
+        .parse()
+        .map_err(|_| RpcError::new(INVALID_PARAMS, format!("{name} must be a hex hash")))
+}
+
+// hex of the consensus encoding
+fn param_decode<T: Decodable>(params: &[Value], index: usize, name: &str) -> Result<T, RpcError> {
+    let bytes = hex::decode(param_str(params, index, name)?)
+        .map_err(|_| RpcError::new(DESERIALIZATION_ERROR, format!("{name} must be hex")))?;
+    T::from_consensus_bytes(&bytes)
+        .map_err(|e| RpcError::new(DESERIALIZATION_ERROR, format!("{name} decode failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{PrivateKey, Signature};
+    use crate::params::ChainParams;
+    use crate::types::TransactionInput;
+
+    fn call(node: &mut Node, method: &str, params: Value) -> Value {
+        handle(
+            node,
+            &json!({ "method": method, "params": params, "id": 1 }),
+        )
+    }
+
+    #[test]
+    fn mines_through_template() {
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let pubkey = hex::encode(PrivateKey::new_key().public_key().consensus_bytes());
+        assert_eq!(call(&mut node, "getblockcount", json!([]))["result"], 0);
+
+        let template = call(&mut node, "getblocktemplate", json!([pubkey]));
+        let bytes = hex::decode(template["result"]["block"].as_str().unwrap()).unwrap();
+        let mut block = Block::from_consensus_bytes(&bytes).unwrap();
+        while !block.header.mine(100_000) {}
+
+        let submitted = call(
+            &mut node,
+            "submitblock",
+            json!([hex::encode(block.consensus_bytes())]),
+        );
+        assert_eq!(submitted["error"], Value::Null);
+        assert_eq!(call(&mut node, "getblockcount", json!([]))["result"], 1);
+
+        let hash = hex_hash(&block.hash());
+        let verbose = call(&mut node, "getblock", json!([hash]));
+        assert_eq!(verbose["result"]["height"], 1);
+        let coinbase = verbose["result"]["tx"][0].as_str().unwrap().to_string();
+        let raw = call(&mut node, "getrawtransaction", json!([coinbase, true]));
+        assert_eq!(raw["result"]["blockhash"], json!(hash));
+
+        let unknown = call(&mut node, "getblock", json!([hex_hash(&Hash::zero())]));
+        assert_eq!(unknown["error"]["code"], INVALID_ADDRESS_OR_KEY);
+        let missing = call(&mut node, "nosuchmethod", json!([]));
+        assert_eq!(missing["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    // mine the template paying `pubkey` and submit it
+    fn mine(node: &mut Node, pubkey: &PublicKey) -> Block {
+        let pubkey = hex::encode(pubkey.consensus_bytes());
+        let template = call(node, "getblocktemplate", json!([pubkey]));
+        let bytes = hex::decode(template["result"]["block"].as_str().unwrap()).unwrap();
+        let mut block = Block::from_consensus_bytes(&bytes).unwrap();
+        while !block.header.mine(100_000) {}
+        let submitted = call(
+            node,
+            "submitblock",
+            json!([hex::encode(block.consensus_bytes())]),
+        );
+        assert_eq!(submitted["error"], Value::Null);
+        block
+    }
+
+    #[test]
+    fn spends_mined_coinbase() {
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let key = PrivateKey::new_key();
+        let block = mine(&mut node, &key.public_key());
+        let output = block.transactions[0].outputs[0].clone();
+
+        let transaction = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_for(&output.hash(), &key, &output.pubkey),
+            }],
+            vec![TransactionOutput {
+                value: output.value,
+                unique_id: Uuid::new_v4(),
+                pubkey: key.public_key(),
+            }],
+        );
+        let sent = call(
+            &mut node,
+            "sendrawtransaction",
+            json!([hex::encode(transaction.consensus_bytes())]),
+        );
+        assert_eq!(sent["result"], json!(hex_hash(&transaction.hash())));
+
+        let block = mine(&mut node, &key.public_key());
+        assert_eq!(block.transactions[1].hash(), transaction.hash());
+        assert_eq!(
+            call(&mut node, "getmempoolinfo", json!([]))["result"]["size"],
+            0
+        );
+        let double_spend = call(
+            &mut node,
+            "sendrawtransaction",
+            json!([hex::encode(transaction.consensus_bytes())]),
+        );
+        assert_eq!(double_spend["error"]["code"], VERIFY_ERROR);
+    }
+
+    #[test]
+    fn writes_private_cookie() {
+        let dir = std::env::temp_dir().join(format!("btclib-cookie-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let auth = RpcAuth::cookie(&dir).unwrap();
+        let (user, password) = RpcAuth::read_cookie(&dir).unwrap();
+        assert_eq!(
+            (user, password.as_str()),
+            (auth.user, auth.password.as_str())
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = fs::metadata(dir.join(COOKIE_FILE)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        RpcAuth::remove_cookie(&dir).unwrap();
+        assert!(RpcAuth::read_cookie(&dir).is_err());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn manages_bans() {
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let peer = "10.0.0.9:9333".parse().unwrap();
+        node.connected(peer, false, std::time::Instant::now());
+        let banned = call(&mut node, "setban", json!(["10.0.0.9", "add", 3600]));
+        assert_eq!(banned["error"], Value::Null);
+        assert!(node.peer_info().is_empty());
+        let list = call(&mut node, "listbanned", json!([]));
+        assert_eq!(list["result"][0]["address"], "10.0.0.9");
+
+        let unban = |node: &mut Node| call(node, "setban", json!(["10.0.0.9", "remove"]));
+        assert_eq!(unban(&mut node)["error"], Value::Null);
+        assert_eq!(
+            unban(&mut node)["error"]["code"],
+            CLIENT_INVALID_IP_OR_SUBNET
+        );
+        call(&mut node, "setban", json!(["10.0.0.9", "add"]));
+        call(&mut node, "clearbanned", json!([]));
+        assert_eq!(
+            call(&mut node, "listbanned", json!([]))["result"],
+            json!([])
+        );
+        assert_eq!(
+            call(&mut node, "setban", json!(["not an ip", "add"]))["error"]["code"],
+            CLIENT_INVALID_IP_OR_SUBNET
+        );
+    }
+
+    #[test]
+    fn requires_credentials() {
+        let auth = RpcAuth::new("alice", "secret");
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let body = r#"{"method":"getdifficulty","id":"x"}"#;
+        let request = |authorization: &str| {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nAuthorization: Basic {authorization}\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            Request::read(&mut raw.as_bytes()).unwrap()
+        };
+        // alice:secret and alice:wrong
+        let ok = respond(&auth, &mut node, &request("YWxpY2U6c2VjcmV0"));
+        assert_eq!(ok.status, 200);
+        let denied = respond(&auth, &mut node, &request("YWxpY2U6d3Jvbmc="));
+        assert_eq!(denied.status, 401);
+    }
+}
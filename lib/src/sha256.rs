@@ -1,14 +1,20 @@
 use std::fmt;
+use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sha256::digest;
 
+use crate::error::BtcError;
 use crate::U256;
 
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
 pub struct Hash(U256);
 
 impl Hash {
+    /// Hash of the CBOR encoding. Not for consensus objects, those are
+    /// hashed over their consensus encoding with `hash_bytes`.
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
         if let Err(e) = ciborium::into_writer(data, &mut serialized) {
@@ -22,6 +28,17 @@ impl Hash {
         Hash(U256::from(hash_array))
     }
 
+    /// Double SHA-256, as used for every consensus hash
+    pub fn hash_bytes(bytes: &[u8]) -> Self {
+        Self::from_digest(Sha256::new().chain_update(bytes))
+    }
+
+    // second round of SHA-256 over the first one
+    fn from_digest(hasher: Sha256) -> Self {
+        let hash_array: [u8; 32] = Sha256::digest(hasher.finalize()).into();
+        Hash(U256::from(hash_array))
+    }
+
     pub fn matches_target(&self, target: U256) -> bool {
         self.0 <= target
     }
@@ -35,6 +52,28 @@ impl Hash {
         self.0.to_little_endian(&mut bytes);
         bytes.as_slice().try_into().unwrap()
     }
+
+    /// Inverse of `as_bytes`
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Hash(U256::from_little_endian(&bytes))
+    }
+}
+
+/// SHA-256 state after absorbing a fixed prefix, so that hashing many
+/// messages sharing it (block headers differing only in the nonce) skips
+/// recompressing the prefix
+#[derive(Clone)]
+pub struct Midstate(Sha256);
+
+impl Midstate {
+    pub fn new(prefix: &[u8]) -> Self {
+        Midstate(Sha256::new().chain_update(prefix))
+    }
+
+    /// Same as `Hash::hash_bytes` of the prefix followed by `suffix`
+    pub fn hash_with_suffix(&self, suffix: &[u8]) -> Hash {
+        Hash::from_digest(self.0.clone().chain_update(suffix))
+    }
 }
 
 impl fmt::Display for Hash {
@@ -51,6 +90,20 @@ impl fmt::LowerHex for Hash {
     }
 }
 
+/// Big-endian hex as printed with `{:064x}`, leading zeros optional
+impl FromStr for Hash {
+    type Err = BtcError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() > 64 {
+            return Err(BtcError::InvalidHash);
+        }
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| BtcError::InvalidHash)
+    }
+}
+
 #[cfg(test)]
 mod hash {
     use super::*;
@@ -69,11 +122,34 @@ mod hash {
         assert_eq!(type_name::<_>(hash.0), "btclib::U256");
     }
 
+    #[test]
+    fn midstate_matches_full_hash() {
+        let message: Vec<u8> = (0..=200).collect();
+        let midstate = Midstate::new(&message[..120]);
+        assert_eq!(
+            midstate.hash_with_suffix(&message[120..]),
+            Hash::hash_bytes(&message)
+        );
+    }
+
     #[test]
     fn zero() {
         assert!(Hash::zero().0.is_zero(), "is zero");
     }
 
+    #[test]
+    fn hex_round_trip() {
+        let hash = Hash::hash_bytes(b"abc");
+        let hex = format!("{hash:064x}");
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex.parse::<Hash>().unwrap(), hash);
+        assert_eq!(
+            format!("{:064x}", Hash::zero()).parse::<Hash>().unwrap(),
+            Hash::zero()
+        );
+        assert!("xyz".parse::<Hash>().is_err());
+    }
+
     fn type_name<T>(_: T) -> &'static str {
         std_type_name::<T>()
     }
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::U256;
+
+/// Bitcoin's 32-bit "nBits" encoding of a target: one byte of size followed by
+/// a 3 byte mantissa, target = mantissa * 256^(size - 3)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CompactTarget(pub u32);
+
+impl CompactTarget {
+    /// Encode a target, dropping the bits the mantissa can not hold
+    pub fn from_target(target: U256) -> Self {
+        let mut size = target.bits().div_ceil(8) as u32;
+        let mut mantissa = if size <= 3 {
+            (target.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (target >> (8 * (size - 3)) as usize).low_u64() as u32
+        };
+
+        // the high mantissa bit is a sign bit, move it to the next byte
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        CompactTarget(mantissa | (size << 24))
+    }
+
+    /// `None` for negative or overflowing encodings
+    pub fn to_target(self) -> Option<U256> {
+        let size = self.0 >> 24;
+        let mantissa = self.0 & 0x007f_ffff;
+
+        if mantissa != 0 && self.0 & 0x0080_0000 != 0 {
+            return None;
+        }
+
+        if size <= 3 {
+            return Some(U256::from(mantissa >> (8 * (3 - size))));
+        }
+
+        let mantissa = U256::from(mantissa);
+        let shift = 8 * (size - 3) as usize;
+        if shift >= 256 || mantissa.bits() + shift > 256 {
+            return None;
+        }
+        Some(mantissa << shift)
+    }
+}
+
+impl fmt::Display for CompactTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// Round a target down to the nearest value representable in compact form
+pub fn normalise(target: U256) -> U256 {
+    CompactTarget::from_target(target)
+        .to_target()
+        .expect("normalise: encoded target always decodes")
+}
+
+/// How many times harder than the easiest target (`MIN_TARGET`) this target is
+pub fn difficulty(target: U256) -> f64 {
+    if target.is_zero() {
+        return f64::INFINITY;
+    }
+    to_f64(crate::MIN_TARGET) / to_f64(target)
+}
+
+/// Expected number of hashes to meet the target, 2^256 / (target + 1)
+pub fn work(target: U256) -> U256 {
+    if target == U256::MAX {
+        return U256::one();
+    }
+    (!target / (target + 1)) + 1
+}
+
+fn to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |acc, limb| {
+        acc * 18_446_744_073_709_551_616.0 + *limb as f64
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            CompactTarget(0x1d00ffff).to_target(),
+            Some(U256::from(0xffffu64) << 208)
+        );
+        assert_eq!(
+            CompactTarget(0x05009234).to_target(),
+            Some(U256::from(0x9234_0000u64))
+        );
+        assert_eq!(
+            CompactTarget(0x01123456).to_target(),
+            Some(U256::from(0x12))
+        );
+        assert_eq!(CompactTarget(0x01003456).to_target(), Some(U256::zero()));
+        // negative
+        assert_eq!(CompactTarget(0x04923456).to_target(), None);
+        // overflow
+        assert_eq!(CompactTarget(0xff123456).to_target(), None);
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0xffffu64) << 208),
+            CompactTarget(0x1d00ffff)
+        );
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0x80)),
+            CompactTarget(0x02008000)
+        );
+        assert_eq!(
+            CompactTarget::from_target(U256::from(0x12)),
+            CompactTarget(0x01120000)
+        );
+        assert_eq!(CompactTarget::from_target(U256::zero()), CompactTarget(0));
+    }
+
+    #[test]
+    fn round_trip() {
+        let target = normalise(crate::MIN_TARGET);
+        assert!(target <= crate::MIN_TARGET);
+        assert_eq!(CompactTarget::from_target(target).to_target(), Some(target));
+        assert_eq!(normalise(target), target);
+    }
+
+    #[test]
+    fn difficulty_and_work() {
+        assert_eq!(difficulty(crate::MIN_TARGET), 1.0);
+        assert_eq!(difficulty(crate::MIN_TARGET / 4), 4.0);
+        assert_eq!(work(U256::MAX), U256::one());
+        assert_eq!(work(U256::MAX >> 1), U256::from(2));
+    }
+}
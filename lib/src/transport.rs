@@ -0,0 +1,524 @@
+//! Optional encrypted transport for `Message` traffic.
+//!
+//! Both ends first exchange one byte with their `Encryption` policy. If both
+//! allow it they exchange ephemeral secp256k1 keys, derive one
+//! ChaCha20-Poly1305 key per direction from the ECDH secret with HKDF, and
+//! prove their long-term identity with an ECDSA signature over the handshake
+//! inside the first encrypted frame. Otherwise messages are sent in the
+//! clear, exactly as `Message::send` frames them.
+//!
+//! Both policy bytes are part of the key derivation, so rewriting either
+//! fails the handshake whenever it still ends up encrypted. Rewriting one to
+//! `Disabled` cannot be detected that way: only `Required` or a pinned peer
+//! protect against a downgrade to plaintext.
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use k256::ecdh::EphemeralSecret;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use sha2::Sha256;
+
+use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::encode::{Decodable, Encodable};
+use crate::network::Message;
+use crate::sha256::Hash;
+use crate::util::Saveable;
+
+const PROTOCOL: &[u8] = b"btclib transport v1";
+// a block is at most MAX_BLOCK_SIZE in consensus encoding, CBOR is larger
+const MAX_FRAME_SIZE: usize = 16 * crate::MAX_BLOCK_SIZE;
+
+/// Whether a connection may, should or must be encrypted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encryption {
+    /// Plaintext only
+    #[default]
+    Disabled,
+    /// Encrypt if the peer supports it, else fall back to plaintext. An
+    /// attacker on the path can force that fallback unless the peer is pinned
+    Preferred,
+    /// Drop peers that do not encrypt
+    Required,
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(Encryption::Disabled),
+            "preferred" => Ok(Encryption::Preferred),
+            "required" => Ok(Encryption::Required),
+            _ => Err(format!("expected disabled, preferred or required, got {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TransportConfig {
+    pub encryption: Encryption,
+    /// Long-term key proving who we are; a throwaway key is used if unset
+    pub identity: Option<PrivateKey>,
+    /// Only talk to a peer holding this key, implies encryption
+    pub pinned_peer: Option<PublicKey>,
+}
+
+/// Load the long-term identity kept at `path`, creating it on first use.
+/// Like the RPC cookie, a new key file is only readable by its owner
+pub fn load_identity(path: &Path) -> IoResult<PrivateKey> {
+    if path.exists() {
+        return PrivateKey::load_from_file(path);
+    }
+    let identity = PrivateKey::new_key();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    identity.save(options.open(path)?)?;
+    Ok(identity)
+}
+
+/// A stream speaking `Message`s, encrypted or not
+pub struct Connection<S> {
+    stream: S,
+    // sending and receiving cipher, None in plaintext
+    session: Option<(Cipher, Cipher)>,
+    peer_identity: Option<PublicKey>,
+}
+
+/// Receiving side of a split `Connection`
+pub struct ReadHalf<S> {
+    stream: S,
+    cipher: Option<Cipher>,
+}
+
+/// Sending side of a split `Connection`
+pub struct WriteHalf<W> {
+    stream: W,
+    cipher: Option<Cipher>,
+}
+
+// one direction of an encrypted session
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: &[u8]) -> Self {
+        Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+}
+
+impl<S: Read + Write> Connection<S> {
+    /// Handshake as the side that opened the connection
+    pub fn connect(stream: S, config: &TransportConfig) -> IoResult<Self> {
+        Self::handshake(stream, config, true)
+    }
+
+    /// Handshake as the side that accepted the connection
+    pub fn accept(stream: S, config: &TransportConfig) -> IoResult<Self> {
+        Self::handshake(stream, config, false)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Identity the peer proved in the handshake, if encrypted
+    pub fn peer_identity(&self) -> Option<&PublicKey> {
+        self.peer_identity.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Split for separate reader and writer threads; `writer` is a second
+    /// handle on the same stream, e.g. from `TcpStream::try_clone`
+    pub fn split<W: Write>(self, writer: W) -> (ReadHalf<S>, WriteHalf<W>) {
+        let (send, receive) = match self.session {
+            Some((send, receive)) => (Some(send), Some(receive)),
+            None => (None, None),
+        };
+        (
+            ReadHalf {
+                stream: self.stream,
+                cipher: receive,
+            },
+            WriteHalf {
+                stream: writer,
+                cipher: send,
+            },
+        )
+    }
+
+    fn handshake(mut stream: S, config: &TransportConfig, initiator: bool) -> IoResult<Self> {
+        let encryption = match config.pinned_peer {
+            Some(_) => Encryption::Required,
+            None => config.encryption,
+        };
+        stream.write_all(&[encryption as u8])?;
+        let mut peer_policy = [0u8];
+        stream.read_exact(&mut peer_policy)?;
+        let peer_encryption = match peer_policy[0] {
+            0 => Encryption::Disabled,
+            1 => Encryption::Preferred,
+            2 => Encryption::Required,
+            _ => return Err(invalid_data("unknown encryption policy")),
+        };
+
+        if encryption == Encryption::Disabled || peer_encryption == Encryption::Disabled {
+            if encryption == Encryption::Required {
+                return Err(invalid_data("peer does not support encryption"));
+            }
+            if peer_encryption == Encryption::Required {
+                return Err(invalid_data("peer requires encryption"));
+            }
+            return Ok(Connection {
+                stream,
+                session: None,
+                peer_identity: None,
+            });
+        }
+
+        // ephemeral ECDH
+        let secret = EphemeralSecret::random(&mut rand::thread_rng());
+        let public = secret.public_key().to_encoded_point(true);
+        stream.write_all(public.as_bytes())?;
+        let mut peer_public = [0u8; 33];
+        stream.read_exact(&mut peer_public)?;
+        let peer_public = k256::PublicKey::from_sec1_bytes(&peer_public)
+            .map_err(|_| invalid_data("invalid handshake key"))?;
+
+        // protocol name, both policies, then both keys, initiator's first
+        let peer_point = peer_public.to_encoded_point(true);
+        let mut transcript = PROTOCOL.to_vec();
+        if initiator {
+            transcript.extend_from_slice(&[encryption as u8, peer_policy[0]]);
+            transcript.extend_from_slice(public.as_bytes());
+            transcript.extend_from_slice(peer_point.as_bytes());
+        } else {
+            transcript.extend_from_slice(&[peer_policy[0], encryption as u8]);
+            transcript.extend_from_slice(peer_point.as_bytes());
+            transcript.extend_from_slice(public.as_bytes());
+        }
+
+        let mut keys = [0u8; 64];
+        secret
+            .diffie_hellman(&peer_public)
+            .extract::<Sha256>(Some(PROTOCOL))
+            .expand(&transcript, &mut keys)
+            .map_err(|_| invalid_data("key derivation failed"))?;
+        let (initiator_key, responder_key) = keys.split_at(32);
+        let (send_key, receive_key) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        let (mut send, mut receive) = (Cipher::new(send_key), Cipher::new(receive_key));
+
+        // prove our identity over the transcript and our role, so the
+        // signature cannot be replayed on another connection or reflected
+        let identity = config.identity.clone().unwrap_or_else(PrivateKey::new_key);
+        let mut proof = identity.public_key().consensus_bytes();
+        Signature::sign_output(&transcript_hash(&transcript, initiator), &identity)
+            .consensus_encode(&mut proof)?;
+        write_frame(&mut stream, &mut send, &proof)?;
+
+        let peer_proof = read_frame(&mut stream, &mut receive)?;
+        let mut reader = &peer_proof[..];
+        let peer_identity = PublicKey::consensus_decode(&mut reader)?;
+        let signature = Signature::consensus_decode(&mut reader)?;
+        if !signature.verify(&transcript_hash(&transcript, !initiator), &peer_identity) {
+            return Err(invalid_data("peer identity proof failed"));
+        }
+        if config
+            .pinned_peer
+            .as_ref()
+            .is_some_and(|pinned| *pinned != peer_identity)
+        {
+            return Err(invalid_data("peer is not the pinned identity"));
+        }
+
+        Ok(Connection {
+            stream,
+            session: Some((send, receive)),
+            peer_identity: Some(peer_identity),
+        })
+    }
+
+    pub fn send(&mut self, message: &Message) -> IoResult<()> {
+        let cipher = self.session.as_mut().map(|(send, _)| send);
+        send_message(&mut self.stream, cipher, message)
+    }
+
+    pub fn receive(&mut self) -> IoResult<Message> {
+        let cipher = self.session.as_mut().map(|(_, receive)| receive);
+        receive_message(&mut self.stream, cipher)
+    }
+}
+
+impl<S: Read> ReadHalf<S> {
+    pub fn receive(&mut self) -> IoResult<Message> {
+        receive_message(&mut self.stream, self.cipher.as_mut())
+    }
+}
+
+impl<W: Write> WriteHalf<W> {
+    pub fn send(&mut self, message: &Message) -> IoResult<()> {
+        send_message(&mut self.stream, self.cipher.as_mut(), message)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.stream
+    }
+}
+
+fn send_message(
+    stream: &mut impl Write,
+    cipher: Option<&mut Cipher>,
+    message: &Message,
+) -> IoResult<()> {
+    let Some(cipher) = cipher else {
+        return message.send(stream).map_err(encode_error);
+    };
+    let bytes = message.encode().map_err(encode_error)?;
+    write_frame(stream, cipher, &bytes)
+}
+
+fn receive_message(stream: &mut impl Read, cipher: Option<&mut Cipher>) -> IoResult<Message> {
+    let Some(cipher) = cipher else {
+        return Message::receive(stream).map_err(decode_error);
+    };
+    let bytes = read_frame(stream, cipher)?;
+    Message::decode(&bytes).map_err(decode_error)
+}
+
+// u32 length of the ciphertext, authenticated as associated data
+fn write_frame(stream: &mut impl Write, cipher: &mut Cipher, plaintext: &[u8]) -> IoResult<()> {
+    let len = (plaintext.len() + 16) as u32;
+    if len as usize > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame too large"));
+    }
+    let len = len.to_be_bytes();
+    let nonce = frame_nonce(&mut cipher.counter)?;
+    let ciphertext = cipher
+        .aead
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &len,
+            },
+        )
+        .map_err(|_| invalid_data("encryption failed"))?;
+    stream.write_all(&len)?;
+    stream.write_all(&ciphertext)
+}
+
+fn read_frame(stream: &mut impl Read, cipher: &mut Cipher) -> IoResult<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let size = u32::from_be_bytes(len) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(invalid_data("frame too large"));
+    }
+    let mut ciphertext = vec![0u8; size];
+    stream.read_exact(&mut ciphertext)?;
+    let nonce = frame_nonce(&mut cipher.counter)?;
+    cipher
+        .aead
+        .decrypt(
+            &nonce,
+            Payload {
+                msg: &ciphertext,
+                aad: &len,
+            },
+        )
+        .map_err(|_| invalid_data("frame failed authentication"))
+}
+
+fn transcript_hash(transcript: &[u8], initiator: bool) -> Hash {
+    let mut bytes = transcript.to_vec();
+    bytes.push(initiator as u8);
+    Hash::hash_bytes(&bytes)
+}
+
+// the frame counter, never reused for a key
+fn frame_nonce(counter: &mut u64) -> IoResult<Nonce> {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    *counter = counter
+        .checked_add(1)
+        .ok_or_else(|| invalid_data("frame counter exhausted"))?;
+    Ok(Nonce::from(nonce))
+}
+
+fn invalid_data(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_string())
+}
+
+fn encode_error(error: ciborium::ser::Error<IoError>) -> IoError {
+    match error {
+        ciborium::ser::Error::Io(error) => error,
+        error => invalid_data(&error.to_string()),
+    }
+}
+
+fn decode_error(error: ciborium::de::Error<IoError>) -> IoError {
+    match error {
+        ciborium::de::Error::Io(error) => error,
+        error => invalid_data(&error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    fn preferred() -> TransportConfig {
+        TransportConfig {
+            encryption: Encryption::Preferred,
+            ..Default::default()
+        }
+    }
+
+    // runs the accepting side on a thread and returns both handshake results
+    fn handshake(
+        server: TransportConfig,
+        client: TransportConfig,
+    ) -> (
+        IoResult<Connection<TcpStream>>,
+        IoResult<Connection<TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Connection::accept(stream, &server)
+        });
+        let client = Connection::connect(TcpStream::connect(address).unwrap(), &client);
+        (server.join().unwrap(), client)
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let identity = PrivateKey::new_key();
+        let server = TransportConfig {
+            identity: Some(identity.clone()),
+            ..preferred()
+        };
+        let client = TransportConfig {
+            pinned_peer: Some(identity.public_key()),
+            ..Default::default()
+        };
+        let (server, client) = handshake(server, client);
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        assert!(client.is_encrypted());
+        assert_eq!(client.peer_identity(), Some(&identity.public_key()));
+
+        client.send(&Message::DiscoverNodes).unwrap();
+        client.send(&Message::Ping(7)).unwrap();
+        assert!(matches!(server.receive().unwrap(), Message::DiscoverNodes));
+        assert!(matches!(server.receive().unwrap(), Message::Ping(7)));
+        server.send(&Message::Pong(7)).unwrap();
+        assert!(matches!(client.receive().unwrap(), Message::Pong(7)));
+
+        // the halves keep counting where the connection left off
+        let writer = server.get_ref().try_clone().unwrap();
+        let (mut server_reader, mut server_writer) = server.split(writer);
+        server_writer.send(&Message::Pong(8)).unwrap();
+        assert!(matches!(client.receive().unwrap(), Message::Pong(8)));
+        client.send(&Message::Ping(9)).unwrap();
+        assert!(matches!(server_reader.receive().unwrap(), Message::Ping(9)));
+    }
+
+    #[test]
+    fn rejects_wrong_pinned_identity() {
+        let client = TransportConfig {
+            pinned_peer: Some(PrivateKey::new_key().public_key()),
+            ..Default::default()
+        };
+        let (_, client) = handshake(preferred(), client);
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn identity_survives_restarts() {
+        let path = std::env::temp_dir().join(format!("btclib-identity-{}", uuid::Uuid::new_v4()));
+        let identity = load_identity(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let reloaded = load_identity(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.public_key(), identity.public_key());
+    }
+
+    #[test]
+    fn falls_back_to_plaintext() {
+        let plaintext = TransportConfig::default();
+        let (server, client) = handshake(plaintext.clone(), preferred());
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        assert!(!client.is_encrypted());
+        client.send(&Message::Ping(1)).unwrap();
+        assert!(matches!(server.receive().unwrap(), Message::Ping(1)));
+
+        let required = TransportConfig {
+            encryption: Encryption::Required,
+            ..Default::default()
+        };
+        let (_, client) = handshake(plaintext, required);
+        assert!(client.is_err());
+    }
+
+    // forwards `address` byte for byte, except the client's policy byte
+    fn rewriting_relay(address: SocketAddr, policy: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            let server = TcpStream::connect(address).unwrap();
+            let (mut client_reader, mut server_writer) =
+                (client.try_clone().unwrap(), server.try_clone().unwrap());
+            thread::spawn(move || {
+                let mut byte = [0u8];
+                client_reader.read_exact(&mut byte).unwrap();
+                server_writer.write_all(&[policy]).unwrap();
+                let _ = std::io::copy(&mut client_reader, &mut server_writer);
+            });
+            let _ = std::io::copy(&mut &server, &mut &client);
+        });
+        relay
+    }
+
+    #[test]
+    fn detects_rewritten_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = rewriting_relay(listener.local_addr().unwrap(), Encryption::Required as u8);
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            Connection::accept(stream, &preferred())
+        });
+        let client = Connection::connect(TcpStream::connect(relay).unwrap(), &preferred());
+        // each side derives keys from a different transcript
+        assert!(client.is_err());
+        assert!(server.join().unwrap().is_err());
+    }
+}
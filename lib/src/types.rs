@@ -1,7 +1,11 @@
 mod block;
 mod blockchain;
+mod compact_block;
+mod header_chain;
 mod transaction;
 
 pub use block::{Block, BlockHeader};
 pub use blockchain::Blockchain;
+pub use compact_block::{CompactBlock, PartialBlock};
+pub use header_chain::{HeaderChain, MAX_HEADERS};
 pub use transaction::{Transaction, TransactionInput, TransactionOutput};
@@ -1,12 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
 use chrono::{DateTime, Utc};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use super::{Transaction, TransactionOutput};
+use crate::amount::Amount;
+use crate::crypto::{PublicKey, Signature, SignatureCache, XOnlyPublicKey};
+use crate::encode::Encodable;
 use crate::error::{BtcError, Result};
-use crate::sha256::Hash;
+use crate::params::ChainParams;
+use crate::sha256::{Hash, Midstate};
+use crate::target::{self, CompactTarget};
 use crate::util::MerkleRoot;
 use crate::util::Saveable;
 use crate::U256;
@@ -27,128 +33,229 @@ impl Block {
 
     pub fn verify_coinbase_transaction(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
     ) -> Result<()> {
-        let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
-            return Err(BtcError::InvalidTransaction);
+        let coinbase_transaction = match self.transactions.first() {
+            Some(transaction) => transaction,
+            None => return Err(BtcError::EmptyBlock { block: self.hash() }),
+        };
+        let tx = coinbase_transaction.hash();
+        if !coinbase_transaction.inputs.is_empty() {
+            return Err(BtcError::MalformedCoinbase {
+                tx,
+                reason: "coinbase must not have inputs",
+            });
         }
-        if coinbase_transaction.outputs.len() == 0 {
-            return Err(BtcError::InvalidTransaction);
+        if coinbase_transaction.outputs.is_empty() {
+            return Err(BtcError::MalformedCoinbase {
+                tx,
+                reason: "coinbase has no outputs",
+            });
         }
 
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
-
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-
-        if total_coinbase_outputs != block_reward + miner_fees {
-            return Err(BtcError::InvalidTransaction);
+        let block_reward = params.block_reward(predicted_block_height);
+
+        let total_coinbase_outputs = coinbase_transaction
+            .output_value()
+            .ok_or(BtcError::ValueOutOfRange { tx })?;
+        let expected = block_reward
+            .checked_add(miner_fees)
+            .ok_or(BtcError::ValueOutOfRange { tx })?;
+
+        if total_coinbase_outputs != expected {
+            return Err(BtcError::CoinbaseMismatch {
+                tx,
+                expected,
+                actual: total_coinbase_outputs,
+            });
         }
 
         Ok(())
     }
 
+    /// Sum of the fees of all but the coinbase; errors name the offending transaction
     pub fn calculate_miner_fees(
         &self,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
-    ) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    ) -> Result<Amount> {
+        let mut inputs: HashSet<Hash> = HashSet::new();
+        let mut outputs: HashSet<Hash> = HashSet::new();
+        let mut fees = Amount::ZERO;
 
         for transaction in self.transactions.iter().skip(1) {
-            for input in &transaction.inputs {
+            let tx = transaction.hash();
+            let mut input_value = Amount::ZERO;
+            for (input_index, input) in transaction.inputs.iter().enumerate() {
+                let output = input.prev_transaction_output_hash;
                 // match inputs to outputs
-                let prev_output = match utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output)
-                {
+                let prev_output = match utxos.get(&output).map(|(_, output)| output) {
                     Some(output) => output,
-                    None => return Err(BtcError::InvalidTransaction),
+                    None => {
+                        return Err(BtcError::MissingInput {
+                            tx,
+                            input: input_index,
+                            output,
+                        })
+                    }
                 };
 
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                if !inputs.insert(output) {
+                    return Err(BtcError::DuplicateInput {
+                        tx,
+                        input: input_index,
+                        output,
+                    });
                 }
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                input_value = input_value
+                    .checked_add(prev_output.value)
+                    .ok_or(BtcError::ValueOutOfRange { tx })?;
             }
 
-            for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
-                    return Err(BtcError::InvalidTransaction);
+            for (output_index, output) in transaction.outputs.iter().enumerate() {
+                if !outputs.insert(output.hash()) {
+                    return Err(BtcError::DuplicateOutput {
+                        tx,
+                        output: output_index,
+                    });
                 }
-                outputs.insert(output.hash(), output.clone());
             }
+            let output_value = transaction
+                .output_value()
+                .ok_or(BtcError::ValueOutOfRange { tx })?;
+
+            let fee = input_value
+                .checked_sub(output_value)
+                .ok_or(BtcError::Overspend {
+                    tx,
+                    inputs: input_value,
+                    outputs: output_value,
+                })?;
+            fees = fees
+                .checked_add(fee)
+                .ok_or(BtcError::ValueOutOfRange { tx })?;
         }
 
-        let input_value: u64 = inputs.values().map(|input| input.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-
-        Ok(input_value - output_value)
+        Ok(fees)
     }
 
+    /// Structural checks run first and in block order. Signatures not found
+    /// in `signature_cache` are then verified in parallel; the error names the
+    /// first bad input in block order, whatever thread finds it.
     pub fn verify_transactions(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
         utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        signature_cache: &SignatureCache,
     ) -> Result<()> {
         let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        // (tx, input index, input, spent output) in block order
+        let mut signature_checks = vec![];
 
         if self.transactions.is_empty() {
-            return Err(BtcError::InvalidTransaction);
+            return Err(BtcError::EmptyBlock { block: self.hash() });
         }
 
-        // verify coinbase transaction
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
-
-        for transaction in &self.transactions {
-            let mut input_value = 0;
-            let mut output_value = 0;
+        for transaction in self.transactions.iter().skip(1) {
+            let tx = transaction.hash();
+            let mut input_value = Amount::ZERO;
 
-            for input in transaction.inputs.iter().skip(1) {
-                let prev_output = match utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .map(|(_, output)| output)
-                {
+            for (input_index, input) in transaction.inputs.iter().enumerate() {
+                let output = input.prev_transaction_output_hash;
+                let prev_output = match utxos.get(&output).map(|(_, output)| output) {
                     Some(output) => output,
-                    None => return Err(BtcError::InvalidTransaction),
+                    None => {
+                        return Err(BtcError::MissingInput {
+                            tx,
+                            input: input_index,
+                            output,
+                        })
+                    }
                 };
 
                 // prevent same block double spending
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                if inputs.contains_key(&output) {
+                    return Err(BtcError::DuplicateInput {
+                        tx,
+                        input: input_index,
+                        output,
+                    });
                 }
 
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
-                    return Err(BtcError::InvalidTransaction);
+                if !signature_cache.contains(&output, &input.signature, &prev_output.pubkey) {
+                    signature_checks.push((tx, input_index, input, prev_output));
                 }
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
+                input_value = input_value
+                    .checked_add(prev_output.value)
+                    .ok_or(BtcError::ValueOutOfRange { tx })?;
+                inputs.insert(output, prev_output.clone());
             }
 
-            for output in &transaction.outputs {
-                output_value += output.value
-            }
+            let output_value = transaction
+                .output_value()
+                .ok_or(BtcError::ValueOutOfRange { tx })?;
 
             if output_value > input_value {
-                return Err(BtcError::InvalidTransaction);
+                return Err(BtcError::Overspend {
+                    tx,
+                    inputs: input_value,
+                    outputs: output_value,
+                });
             }
         }
 
+        // verify coinbase transaction, the fees are known to be sane by now
+        self.verify_coinbase_transaction(params, predicted_block_height, utxos)?;
+
+        // Schnorr signatures are checked together first; only if the batch
+        // fails are they verified one by one to find the culprit
+        let schnorr_batch: Vec<_> = signature_checks
+            .iter()
+            .filter_map(|(_, _, input, prev_output)| {
+                match (&input.signature, &prev_output.pubkey) {
+                    (Signature::Schnorr(signature), PublicKey::Schnorr(key)) => {
+                        Some((&input.prev_transaction_output_hash, signature, key))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let schnorr_batch_valid =
+            !schnorr_batch.is_empty() && XOnlyPublicKey::verify_batch(&schnorr_batch);
+
+        let first_invalid =
+            signature_checks
+                .par_iter()
+                .position_first(|(_, _, input, prev_output)| {
+                    let batched = matches!(
+                        (&input.signature, &prev_output.pubkey),
+                        (Signature::Schnorr(_), PublicKey::Schnorr(_))
+                    );
+                    if batched && schnorr_batch_valid {
+                        return false;
+                    }
+                    !input
+                        .signature
+                        .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
+                });
+        if let Some(position) = first_invalid {
+            let (tx, input_index, _, _) = signature_checks[position];
+            return Err(BtcError::BadSignature {
+                tx,
+                input: input_index,
+            });
+        }
+
         Ok(())
     }
 
+    /// A block is identified by its header, which commits to the
+    /// transactions through the merkle root
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
     }
 }
 
@@ -162,8 +269,8 @@ pub struct BlockHeader {
     pub prev_block_hash: Hash,
     /// Merkle root of the block's transactions
     pub merkle_root: MerkleRoot,
-    /// target
-    pub target: U256,
+    /// Compact encoding of the target the hash has to meet
+    pub bits: CompactTarget,
 }
 
 impl BlockHeader {
@@ -179,28 +286,115 @@ impl BlockHeader {
             nonce,
             prev_block_hash,
             merkle_root,
-            target,
+            bits: CompactTarget::from_target(target),
         }
     }
 
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.consensus_bytes())
+    }
+
+    /// SHA-256 state over everything but the trailing nonce
+    pub fn midstate(&self) -> Midstate {
+        let bytes = self.consensus_bytes();
+        Midstate::new(&bytes[..bytes.len() - size_of::<u64>()])
     }
 
+    /// Target expanded from `bits`, zero (unmineable) if the encoding is invalid
+    pub fn target(&self) -> U256 {
+        self.bits.to_target().unwrap_or_default()
+    }
+
+    pub fn difficulty(&self) -> f64 {
+        target::difficulty(self.target())
+    }
+
+    /// Expected number of hashes needed to mine this header
+    pub fn work(&self) -> U256 {
+        target::work(self.target())
+    }
+
+    /// Context-dependent header checks shared by full and header-only chains:
+    /// link to the tip, the expected target, proof of work, and a timestamp
+    /// after the median of `ancestor_timestamps` (the most recent
+    /// `MEDIAN_TIME_PAST_WINDOW`) but at most `MAX_FUTURE_BLOCK_TIME` past `now`
+    pub fn validate(
+        &self,
+        tip: Hash,
+        ancestor_timestamps: &[DateTime<Utc>],
+        target: U256,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        let block = self.hash();
+        if self.prev_block_hash != tip {
+            return Err(BtcError::PrevBlockMismatch {
+                block,
+                expected: tip,
+                found: self.prev_block_hash,
+            });
+        }
+
+        // a miner must not pick its own (easier) target
+        let expected_bits = CompactTarget::from_target(target);
+        if self.bits != expected_bits {
+            return Err(BtcError::WrongTarget {
+                block,
+                expected: expected_bits,
+                found: self.bits,
+            });
+        }
+
+        // pow
+        if !block.matches_target(target) {
+            return Err(BtcError::InsufficientProofOfWork {
+                block,
+                target: self.bits,
+            });
+        }
+
+        // must be strictly after the median of the recent blocks
+        if let Some(median) = median_timestamp(ancestor_timestamps) {
+            if self.timestamp <= median {
+                return Err(BtcError::TimestampTooOld {
+                    block,
+                    timestamp: self.timestamp,
+                    median,
+                });
+            }
+        }
+
+        let max_timestamp = now + chrono::Duration::seconds(crate::MAX_FUTURE_BLOCK_TIME as i64);
+        if self.timestamp > max_timestamp {
+            return Err(BtcError::TimestampTooNew {
+                block,
+                timestamp: self.timestamp,
+                max: max_timestamp,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Try `steps` nonces. When the nonce space runs out the timestamp is
+    /// refreshed, but never moved backwards: the template timestamp is already
+    /// past the median time past and the local clock stays within the future limit.
     pub fn mine(&mut self, steps: usize) -> bool {
-        if self.hash().matches_target(self.target) {
+        let target = self.target();
+        if self.hash().matches_target(target) {
             return true;
         }
 
+        let mut midstate = self.midstate();
         for _ in 0..steps {
             if let Some(new_nonce) = self.nonce.checked_add(1) {
                 self.nonce = new_nonce;
             } else {
                 self.nonce = 0;
-                self.timestamp = Utc::now()
+                self.timestamp = Utc::now().max(self.timestamp);
+                midstate = self.midstate();
             }
-            if self.hash().matches_target(self.target) {
-                println!("nonce: {}", self.nonce);
+            let hash = midstate.hash_with_suffix(&self.nonce.to_le_bytes());
+            if hash.matches_target(target) {
                 return true;
             }
         }
@@ -208,6 +402,32 @@ impl BlockHeader {
     }
 }
 
+/// Median of the last `MEDIAN_TIME_PAST_WINDOW` timestamps, `None` if there are none
+pub(crate) fn median_timestamp(timestamps: &[DateTime<Utc>]) -> Option<DateTime<Utc>> {
+    let window = timestamps.len().min(crate::MEDIAN_TIME_PAST_WINDOW);
+    let mut recent = timestamps[timestamps.len() - window..].to_vec();
+    recent.sort_unstable();
+    recent.get(recent.len() / 2).copied()
+}
+
+/// Heights of a `GetHeaders` locator over `len` blocks, tip first
+pub(crate) fn locator_heights(len: usize) -> Vec<usize> {
+    let mut heights = vec![];
+    let mut height = len - 1;
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            break;
+        }
+        if heights.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    heights
+}
+
 impl Saveable for Block {
     fn load<I: Read>(reader: I) -> IoResult<Self> {
         ciborium::de::from_reader(reader)
@@ -1,35 +1,68 @@
 use std::collections::{HashMap, HashSet};
 use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
 
-use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::amount::Amount;
+use crate::crypto::SignatureCache;
+use crate::difficulty::DifficultyAlgorithm;
+use crate::encode::Encodable;
 use crate::error::{BtcError, Result};
+use crate::filter::{BlockFilter, MAX_FILTERS};
+use crate::params::ChainParams;
 use crate::sha256::Hash;
-use crate::util::MerkleRoot;
-use crate::util::Saveable;
-use crate::U256;
+use crate::target;
+use crate::util::{MerkleProof, MerkleRoot};
+use crate::util::{NetworkTime, Saveable};
+use crate::{MAX_BLOCK_SIZE, U256};
 
-use super::{Block, Transaction, TransactionOutput};
+use super::block::{locator_heights, median_timestamp};
+use super::{Block, BlockHeader, Transaction, TransactionOutput, MAX_HEADERS};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
+    // unspent outputs by output hash, marked when a mempool transaction spends them
     utxos: HashMap<Hash, (bool, TransactionOutput)>,
     target: U256,
     blocks: Vec<Block>,
+    #[serde(default)]
+    params: ChainParams,
+    // compact filter per block and the header chain committing to them
+    #[serde(default)]
+    filters: Vec<BlockFilter>,
+    #[serde(default)]
+    filter_headers: Vec<Hash>,
     #[serde(default, skip_serializing)]
     mempool: Vec<(DateTime<Utc>, Transaction)>,
+    #[serde(skip)]
+    network_time: NetworkTime,
+    #[serde(skip)]
+    signature_cache: SignatureCache,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new(ChainParams::default())
+    }
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
-        Blockchain {
+    /// A chain holding only the genesis block of the network
+    pub fn new(params: ChainParams) -> Self {
+        let mut blockchain = Blockchain {
             utxos: HashMap::new(),
-            target: crate::MIN_TARGET,
+            target: params.max_target(),
             blocks: vec![],
+            filters: vec![],
+            filter_headers: vec![],
+            params,
             mempool: vec![],
-        }
+            network_time: NetworkTime::new(),
+            signature_cache: SignatureCache::new(),
+        };
+        blockchain.push_block(blockchain.params.genesis_block());
+        blockchain
     }
 
     pub fn block_height(&self) -> u64 {
@@ -44,60 +77,258 @@ impl Blockchain {
         self.target
     }
 
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// Difficulty of the next block
+    pub fn difficulty(&self) -> f64 {
+        target::difficulty(self.target)
+    }
+
+    /// Total expected hashes spent on the chain
+    pub fn chain_work(&self) -> U256 {
+        self.blocks
+            .iter()
+            .fold(U256::zero(), |work, block| work + block.header.work())
+    }
+
     pub fn blocks(&self) -> impl Iterator<Item = &Block> {
         self.blocks.iter()
     }
 
+    /// Hashes to send in `GetHeaders`, like `HeaderChain::locator`
+    pub fn locator(&self) -> Vec<Hash> {
+        locator_heights(self.blocks.len())
+            .into_iter()
+            .map(|height| self.blocks[height].hash())
+            .collect()
+    }
+
+    /// Block with the given hash, if it is in the chain
+    pub fn block(&self, hash: &Hash) -> Option<&Block> {
+        self.blocks.iter().find(|block| block.hash() == *hash)
+    }
+
+    /// Answer to `GetHeaders`: up to `MAX_HEADERS` headers following the
+    /// first locator hash found in the chain, or following genesis if none is
+    pub fn headers_after(&self, locator: &[Hash]) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.blocks.iter().position(|block| block.hash() == *hash))
+            .unwrap_or(0);
+        self.blocks[start + 1..]
+            .iter()
+            .take(MAX_HEADERS)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// Height and header of the block containing `transaction`, with a
+    /// proof of its inclusion
+    pub fn merkle_proof(&self, transaction: &Hash) -> Option<(u64, &BlockHeader, MerkleProof)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(height, block)| {
+                let index = block
+                    .transactions
+                    .iter()
+                    .position(|tx| tx.hash() == *transaction)?;
+                let proof = MerkleProof::generate(block, index)?;
+                Some((height as u64, &block.header, proof))
+            })
+    }
+
     pub fn mempool(&self) -> &[(chrono::DateTime<Utc>, Transaction)] {
         &self.mempool
     }
 
+    pub fn network_time(&self) -> &NetworkTime {
+        &self.network_time
+    }
+
+    pub fn network_time_mut(&mut self) -> &mut NetworkTime {
+        &mut self.network_time
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_PAST_WINDOW` blocks
+    pub fn median_time_past(&self) -> Option<DateTime<Utc>> {
+        median_timestamp(&self.recent_timestamps())
+    }
+
+    fn recent_timestamps(&self) -> Vec<DateTime<Utc>> {
+        let window = self.blocks.len().min(crate::MEDIAN_TIME_PAST_WINDOW);
+        self.blocks[self.blocks.len() - window..]
+            .iter()
+            .map(|block| block.header.timestamp)
+            .collect()
+    }
+
+    /// Earliest valid timestamp for the next block, or the adjusted clock if later
+    pub fn next_block_timestamp(&self) -> DateTime<Utc> {
+        let now = self.network_time.now();
+        match self.median_time_past() {
+            Some(median) if median >= now => median + chrono::Duration::seconds(1),
+            _ => now,
+        }
+    }
+
+    /// Filters for the blocks from `start`, at most `MAX_FILTERS`, each with its block hash
+    pub fn filters_from(&self, start: u64) -> Vec<(Hash, BlockFilter)> {
+        self.blocks
+            .iter()
+            .zip(&self.filters)
+            .skip(start as usize)
+            .take(MAX_FILTERS)
+            .map(|(block, filter)| (block.hash(), filter.clone()))
+            .collect()
+    }
+
+    /// Filter headers for the blocks from `start`, at most `MAX_FILTERS`
+    pub fn filter_headers_from(&self, start: u64) -> Vec<Hash> {
+        self.filter_headers
+            .iter()
+            .skip(start as usize)
+            .take(MAX_FILTERS)
+            .copied()
+            .collect()
+    }
+
+    /// Recompute block filters, e.g. for a chain saved before they existed
+    pub fn rebuild_filters(&mut self) {
+        self.filters.clear();
+        self.filter_headers.clear();
+        for block in &self.blocks {
+            let filter = BlockFilter::build(block);
+            let prev_header = self.filter_headers.last().copied().unwrap_or(Hash::zero());
+            self.filter_headers.push(filter.header(&prev_header));
+            self.filters.push(filter);
+        }
+    }
+
+    // append a validated block, its filter and its effect on the utxos
+    fn push_block(&mut self, block: Block) {
+        let filter = BlockFilter::build(&block);
+        let prev_header = self.filter_headers.last().copied().unwrap_or(Hash::zero());
+        self.filter_headers.push(filter.header(&prev_header));
+        self.filters.push(filter);
+        Self::apply_block(&mut self.utxos, &block);
+        self.blocks.push(block);
+    }
+
+    // spend the block's inputs and add its outputs
+    fn apply_block(utxos: &mut HashMap<Hash, (bool, TransactionOutput)>, block: &Block) {
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                utxos.remove(&input.prev_transaction_output_hash);
+            }
+            for output in transaction.outputs.iter() {
+                utxos.insert(output.hash(), (false, output.clone()));
+            }
+        }
+    }
+
+    /// Recompute the unspent outputs from the blocks, then mark those the
+    /// mempool spends. They are keyed by `TransactionOutput::hash`, which is
+    /// what inputs reference and sign; a transaction hash would not tell its
+    /// outputs apart.
     pub fn rebuild_utxos(&mut self) {
+        self.utxos.clear();
         for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
-                }
-                for output in transaction.outputs.iter() {
-                    self.utxos
-                        .insert(transaction.hash(), (false, output.clone()));
-                }
+            Self::apply_block(&mut self.utxos, block);
+        }
+        for (_, transaction) in &self.mempool {
+            for input in &transaction.inputs {
+                self.utxos
+                    .entry(input.prev_transaction_output_hash)
+                    .and_modify(|(marked, _)| *marked = true);
             }
         }
     }
 
+    /// Header checks that depend on the current tip: link to the parent,
+    /// the target the chain expects next and proof of work against it
+    pub fn validate_header(&self, header: &BlockHeader) -> Result<()> {
+        let Some(last_block) = self.blocks.last() else {
+            return Err(BtcError::BadGenesis {
+                block: header.hash(),
+            });
+        };
+        header.validate(
+            last_block.hash(),
+            &self.recent_timestamps(),
+            self.target,
+            self.network_time.now(),
+        )
+    }
+
     pub fn add_block(&mut self, block: Block) -> Result<()> {
         // check if the block is valid
         if self.blocks.is_empty() {
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
+            // only the hard-coded genesis block may start the chain
+            if block.hash() != self.params.genesis_block().hash() {
+                return Err(BtcError::BadGenesis {
+                    block: block.header.hash(),
+                });
             }
         } else {
-            let last_block = self.blocks.last().unwrap();
-            if block.header.prev_block_hash != last_block.hash() {
-                println!("prev block hash is wrong");
-                return Err(BtcError::InvalidBlock);
+            let size = block.consensus_size();
+            if size > MAX_BLOCK_SIZE {
+                return Err(BtcError::BlockTooLarge {
+                    block: block.hash(),
+                    size,
+                });
             }
 
-            // pow
-            if !block.header.hash().matches_target(block.header.target) {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
+            self.validate_header(&block.header)?;
+
+            if block.transactions.is_empty() {
+                return Err(BtcError::EmptyBlock {
+                    block: block.hash(),
+                });
             }
 
-            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
+            // a repeated transaction could otherwise leave the merkle root
+            // unchanged (CVE-2012-2459)
+            let mut transactions = HashSet::new();
+            for transaction in &block.transactions {
+                if !transactions.insert(transaction.hash()) {
+                    return Err(BtcError::DuplicateTransaction {
+                        block: block.hash(),
+                        tx: transaction.hash(),
+                    });
+                }
+            }
+
+            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions)?;
 
             if calculated_merkle_root != block.header.merkle_root {
-                println!("invalid merkle root");
-                return Err(BtcError::InvalidBlock);
+                return Err(BtcError::BadMerkleRoot {
+                    block: block.header.hash(),
+                    expected: calculated_merkle_root,
+                    found: block.header.merkle_root,
+                });
             }
 
-            if block.header.timestamp < last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            block.verify_transactions(
+                &self.params,
+                self.block_height(),
+                &self.utxos,
+                &self.signature_cache,
+            )?;
+
+            // the inputs are spent now, their signatures will not be seen again
+            for input in block.transactions.iter().flat_map(|tx| &tx.inputs) {
+                if let Some((_, output)) = self.utxos.get(&input.prev_transaction_output_hash) {
+                    self.signature_cache.remove(
+                        &input.prev_transaction_output_hash,
+                        &input.signature,
+                        &output.pubkey,
+                    );
+                }
             }
-
-            block.verify_transactions(self.block_height(), &self.utxos)?
         }
         let block_transactions: HashSet<_> =
             block.transactions.iter().map(|tx| tx.hash()).collect();
@@ -105,25 +336,102 @@ impl Blockchain {
         self.mempool
             .retain(|(_, tx)| !block_transactions.contains(&tx.hash())); // use retain
 
-        self.blocks.push(block);
+        self.push_block(block);
+        self.try_adjust_target();
+
+        // drop mempool transactions that spend an output the block spent,
+        // releasing their other inputs
+        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
+        let utxos = &self.utxos;
+        self.mempool.retain(|(_, transaction)| {
+            let inputs = || {
+                transaction
+                    .inputs
+                    .iter()
+                    .map(|input| input.prev_transaction_output_hash)
+            };
+            if inputs().all(|output| utxos.contains_key(&output)) {
+                return true;
+            }
+            utxo_hashes_to_unmark.extend(inputs());
+            false
+        });
+        for hash in utxo_hashes_to_unmark {
+            self.utxos
+                .entry(hash)
+                .and_modify(|(marked, _)| *marked = false);
+        }
 
         Ok(())
     }
 
-    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // validate transaction before insert
+    /// Validate a transaction against the UTXO set without touching the
+    /// mempool, as done for Dandelion stem transactions
+    pub fn check_transaction(&mut self, transaction: &Transaction) -> Result<()> {
         // all inputs must have known UTXOs, and should be uniq
+        let tx = transaction.hash();
+        let size = transaction.consensus_size();
+        if size > MAX_BLOCK_SIZE {
+            return Err(BtcError::TransactionTooLarge { tx, size });
+        }
         let mut known_inputs = HashSet::new();
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+        for (input_index, input) in transaction.inputs.iter().enumerate() {
+            let output = input.prev_transaction_output_hash;
+            if !self.utxos.contains_key(&output) {
+                return Err(BtcError::MissingInput {
+                    tx,
+                    input: input_index,
+                    output,
+                });
+            }
+            if known_inputs.contains(&output) {
+                return Err(BtcError::DuplicateInput {
+                    tx,
+                    input: input_index,
+                    output,
+                });
             }
-            if known_inputs.contains(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
+            known_inputs.insert(output);
+        }
+
+        for (input_index, input) in transaction.inputs.iter().enumerate() {
+            let output = input.prev_transaction_output_hash;
+            let pubkey = &self.utxos[&output].1.pubkey;
+            if !self
+                .signature_cache
+                .verify(&output, &input.signature, pubkey)
+            {
+                return Err(BtcError::BadSignature {
+                    tx,
+                    input: input_index,
+                });
             }
-            known_inputs.insert(input.prev_transaction_output_hash);
         }
 
+        let all_inputs = Amount::checked_sum(transaction.inputs.iter().map(|input| {
+            self.utxos
+                .get(&input.prev_transaction_output_hash)
+                .expect("check_transaction: all_inputs failed")
+                .1
+                .value
+        }))
+        .ok_or(BtcError::ValueOutOfRange { tx })?;
+        let all_outputs = transaction
+            .output_value()
+            .ok_or(BtcError::ValueOutOfRange { tx })?;
+        if all_inputs < all_outputs {
+            return Err(BtcError::Overspend {
+                tx,
+                inputs: all_inputs,
+                outputs: all_outputs,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
+        self.check_transaction(&transaction)?;
+
         for input in &transaction.inputs {
             if let Some((true, _)) = self.utxos.get(&input.prev_transaction_output_hash) {
                 let ref_transaction =
@@ -156,23 +464,6 @@ impl Blockchain {
             }
         }
 
-        let all_inputs = transaction
-            .inputs
-            .iter()
-            .map(|input| {
-                &self
-                    .utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("add_to_mempool: all_inputs failed")
-                    .1
-                    .value
-            })
-            .sum::<u64>();
-        let all_outputs = transaction.outputs.iter().map(|output| output.value).sum();
-        if all_inputs < all_outputs {
-            return Err(BtcError::InvalidTransaction);
-        }
-
         // mark UTXO as used
         for input in &transaction.inputs {
             self.utxos
@@ -185,22 +476,20 @@ impl Blockchain {
 
         // sort by miner fee
         self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                        .get(&input.prev_transaction_output_hash)
-                        .expect("add_to_mempool: sort failed")
-                        .1
-                        .value
-                })
-                .sum::<u64>();
-
-            let all_outputs: u64 = transaction.outputs.iter().map(|output| output.value).sum();
-
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
+            let all_inputs = Amount::checked_sum(transaction.inputs.iter().map(|input| {
+                self.utxos
+                    .get(&input.prev_transaction_output_hash)
+                    .expect("add_to_mempool: sort failed")
+                    .1
+                    .value
+            }));
+            let all_outputs = transaction.output_value();
+
+            // both were checked when the transaction entered the mempool
+            all_inputs
+                .zip(all_outputs)
+                .and_then(|(inputs, outputs)| inputs.checked_sub(outputs))
+                .unwrap_or(Amount::ZERO)
         });
         Ok(())
     }
@@ -211,7 +500,7 @@ impl Blockchain {
 
         self.mempool.retain(|(timestamp, transaction)| {
             if now - *timestamp
-                > chrono::Duration::seconds(crate::MAX_MEMPOOL_TRANSACTION_AGE as i64)
+                > chrono::Duration::seconds(self.params.max_mempool_transaction_age as i64)
             {
                 utxo_hashes_to_unmark.extend(
                     transaction
@@ -232,48 +521,21 @@ impl Blockchain {
         }
     }
 
+    /// Recompute the target for the next block with the chain's difficulty algorithm
     pub fn try_adjust_target(&mut self) {
         if self.blocks.is_empty() {
             return;
         }
 
-        if self.blocks.len() % crate::DIFFICULTY_UPDATE_INTERVAL as usize != 0 {
-            return;
-        }
-
-        let start_time = self.blocks
-            [self.blocks.len() - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time = self.blocks.last().unwrap().header.timestamp;
-        let time_diff_seconds = (end_time - start_time).num_seconds(); // delta
-        let target_seconds = crate::IDEAL_BLOCK_TIME * crate::DIFFICULTY_UPDATE_INTERVAL;
-
-        // multiply current target
-        let new_target = BigDecimal::parse_bytes(&self.target.to_string().as_bytes(), 10)
-            .expect("try_adjust_target: BigDecimal::parse_bytes failed")
-            * (BigDecimal::from(time_diff_seconds) / BigDecimal::from(target_seconds));
-
-        let new_target_str = new_target
-            .to_string()
-            .split('.')
-            .next()
-            .expect("expect decimal point")
-            .to_owned();
-
-        let new_target: U256 = U256::from_str_radix(&new_target_str, 10)
-            .expect("try_adjust_target: U256::from_str_radix failed");
-
-        // max factor :D - 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
-        };
+        let blocks = &self.blocks;
+        let new_target = self
+            .params
+            .difficulty
+            .next_target(self.block_height(), &|height| {
+                &blocks[height as usize].header
+            });
 
-        self.target = new_target.min(crate::MIN_TARGET)
+        self.target = target::normalise(new_target.clamp(U256::one(), self.params.max_target()))
     }
 }
 
@@ -287,3 +549,433 @@ impl Saveable for Blockchain {
             .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to seriazlize Blockchain"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::crypto::{PrivateKey, PublicKey, Signature};
+    use crate::difficulty::{DifficultyAdjustment, Windowed};
+    use crate::target::CompactTarget;
+    use crate::types::{HeaderChain, TransactionInput, TransactionOutput};
+
+    fn spend(output: &TransactionOutput, signer: &PrivateKey) -> Transaction {
+        Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_for(&output.hash(), signer, &output.pubkey),
+            }],
+            vec![TransactionOutput {
+                value: output.value,
+                unique_id: Uuid::new_v4(),
+                pubkey: signer.public_key(),
+            }],
+        )
+    }
+
+    fn mine_next_block(blockchain: &Blockchain) -> Block {
+        mine_block(blockchain, PrivateKey::new_key().public_key(), vec![])
+    }
+
+    // spends must not pay fees
+    fn mine_block(blockchain: &Blockchain, pubkey: PublicKey, spends: Vec<Transaction>) -> Block {
+        let mut transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: blockchain.params().block_reward(blockchain.block_height()),
+                unique_id: Uuid::new_v4(),
+                pubkey,
+            }],
+        )];
+        transactions.extend(spends);
+        let mut header = BlockHeader::new(
+            blockchain.next_block_timestamp(),
+            0,
+            blockchain.blocks().last().unwrap().hash(),
+            MerkleRoot::calculate(&transactions).unwrap(),
+            blockchain.target(),
+        );
+        while !header.mine(1000) {}
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn starts_with_genesis() {
+        let params = ChainParams::regtest();
+        let blockchain = Blockchain::new(params.clone());
+        assert_eq!(blockchain.block_height(), 1);
+        assert_eq!(
+            blockchain.blocks().next().unwrap().hash(),
+            params.genesis_block().hash()
+        );
+    }
+
+    #[test]
+    fn rejects_other_genesis() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let mut block = ChainParams::regtest().genesis_block();
+        block.header.nonce += 1;
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::PrevBlockMismatch { .. })
+        ));
+
+        let mut empty = Blockchain {
+            blocks: vec![],
+            filters: vec![],
+            filter_headers: vec![],
+            ..Blockchain::new(ChainParams::regtest())
+        };
+        assert!(matches!(
+            empty.add_block(ChainParams::mainnet().genesis_block()),
+            Err(BtcError::BadGenesis { .. })
+        ));
+        assert!(empty
+            .add_block(ChainParams::regtest().genesis_block())
+            .is_ok());
+    }
+
+    #[test]
+    fn accepts_mined_block() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let block = mine_next_block(&blockchain);
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.block_height(), 2);
+    }
+
+    #[test]
+    fn tracks_utxos_across_blocks() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let alice = PrivateKey::new_key();
+        let block = mine_block(&blockchain, alice.public_key(), vec![]);
+        let coinbase = block.transactions[0].outputs[0].clone();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(
+            blockchain
+                .utxos()
+                .get(&coinbase.hash())
+                .map(|(marked, _)| *marked),
+            Some(false)
+        );
+
+        // the block confirms a different spend of the output the mempool spends
+        blockchain.add_to_mempool(spend(&coinbase, &alice)).unwrap();
+        let confirmed = spend(&coinbase, &alice);
+        let block = mine_block(&blockchain, alice.public_key(), vec![confirmed.clone()]);
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.mempool().is_empty());
+        assert!(!blockchain.utxos().contains_key(&coinbase.hash()));
+        assert!(blockchain
+            .utxos()
+            .contains_key(&confirmed.outputs[0].hash()));
+
+        let utxos = blockchain.utxos().clone();
+        blockchain.rebuild_utxos();
+        assert_eq!(blockchain.utxos().len(), utxos.len());
+        assert!(utxos
+            .keys()
+            .all(|output| blockchain.utxos().contains_key(output)));
+    }
+
+    #[test]
+    fn rejects_duplicated_transaction() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let mut block = mine_next_block(&blockchain);
+        // [a] and [a, a] share a merkle root, the header stays valid
+        block.transactions.push(block.transactions[0].clone());
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::DuplicateTransaction { .. })
+        ));
+    }
+
+    #[test]
+    fn light_client_follows_chain() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..3 {
+            let block = mine_next_block(&blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+
+        let mut headers = HeaderChain::new(ChainParams::regtest());
+        let mut bad = blockchain.headers_after(&headers.locator());
+        bad.swap(0, 1);
+        assert!(matches!(
+            headers.add_headers(bad),
+            Err(BtcError::PrevBlockMismatch { .. })
+        ));
+
+        let mut headers = HeaderChain::new(ChainParams::regtest());
+        headers
+            .add_headers(blockchain.headers_after(&headers.locator()))
+            .unwrap();
+        assert_eq!(headers.height(), blockchain.block_height());
+        assert!(blockchain.headers_after(&headers.locator()).is_empty());
+
+        let coinbase = blockchain.blocks().nth(2).unwrap().transactions[0].hash();
+        let (height, _, proof) = blockchain.merkle_proof(&coinbase).unwrap();
+        assert!(headers.verify_merkle_proof(height, &proof));
+        assert!(!headers.verify_merkle_proof(height + 1, &proof));
+    }
+
+    #[test]
+    fn rejects_easier_target() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
+        // regtest's max target is far easier than mainnet's
+        let mut block = mine_next_block(&Blockchain::new(ChainParams::regtest()));
+        block.header.prev_block_hash = blockchain.blocks().last().unwrap().hash();
+        while !block.header.mine(1000) {}
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::WrongTarget { .. })
+        ));
+    }
+
+    // re-mined at `timestamp`
+    fn mine_at(blockchain: &Blockchain, timestamp: DateTime<Utc>) -> Block {
+        let mut block = mine_next_block(blockchain);
+        block.header.timestamp = timestamp;
+        while !block.header.mine(1000) {}
+        block
+    }
+
+    #[test]
+    fn rejects_timestamp_at_median_time_past() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..3 {
+            let block = mine_next_block(&blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+        let median = blockchain.median_time_past().unwrap();
+        assert!(matches!(
+            blockchain.add_block(mine_at(&blockchain, median)),
+            Err(BtcError::TimestampTooOld { .. })
+        ));
+        let later = median + chrono::Duration::seconds(1);
+        blockchain.add_block(mine_at(&blockchain, later)).unwrap();
+    }
+
+    #[test]
+    fn rejects_timestamp_ahead_of_adjusted_time() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let future = Utc::now()
+            + chrono::Duration::seconds(crate::MAX_FUTURE_BLOCK_TIME as i64)
+            + chrono::Duration::minutes(5);
+        assert!(matches!(
+            blockchain.add_block(mine_at(&blockchain, future)),
+            Err(BtcError::TimestampTooNew { .. })
+        ));
+
+        // peers an hour ahead move the limit with them
+        for _ in 0..5 {
+            let peer_time = Utc::now() + chrono::Duration::hours(1);
+            blockchain.network_time_mut().add_sample(peer_time);
+        }
+        blockchain.add_block(mine_at(&blockchain, future)).unwrap();
+    }
+
+    #[test]
+    fn rejects_wrong_bits() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        // harder than required still has to match the expected bits
+        let mut block = mine_next_block(&blockchain);
+        block.header.bits = CompactTarget::from_target(blockchain.target() / 2);
+        while !block.header.mine(1000) {}
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::WrongTarget { .. })
+        ));
+    }
+
+    #[test]
+    fn retargets_after_interval() {
+        let params = ChainParams {
+            difficulty: DifficultyAdjustment::Windowed(Windowed {
+                interval: 4,
+                block_time: 600,
+                max_factor: 4,
+            }),
+            ..ChainParams::regtest()
+        };
+        let mut blockchain = Blockchain::new(params);
+        let initial = blockchain.target();
+        // the first window spans the old genesis timestamp, the target stays at the max
+        for _ in 0..6 {
+            let block = mine_next_block(&blockchain);
+            blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(blockchain.target(), initial);
+
+        // four blocks in seconds instead of 40 minutes: the most it may harden
+        let block = mine_next_block(&blockchain);
+        let stale_bits = block.header.bits;
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.target(), target::normalise(initial / 4));
+
+        let mut block = mine_next_block(&blockchain);
+        block.header.bits = stale_bits;
+        assert!(matches!(
+            blockchain.add_block(block),
+            Err(BtcError::WrongTarget { .. })
+        ));
+    }
+
+    #[test]
+    fn reports_first_bad_signature() {
+        let alice = PrivateKey::new_key();
+        let mallory = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..3 {
+            let block = mine_block(&blockchain, alice.public_key(), vec![]);
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain.rebuild_utxos();
+
+        let coins: Vec<TransactionOutput> = blockchain
+            .blocks()
+            .skip(1)
+            .map(|block| block.transactions[0].outputs[0].clone())
+            .collect();
+        let spends = vec![
+            spend(&coins[0], &alice),
+            spend(&coins[1], &mallory),
+            spend(&coins[2], &mallory),
+        ];
+        let second = spends[1].hash();
+
+        let block = mine_block(&blockchain, alice.public_key(), spends);
+        match blockchain.add_block(block) {
+            Err(BtcError::BadSignature { tx, input }) => {
+                assert_eq!(tx, second);
+                assert_eq!(input, 0);
+            }
+            other => panic!("expected bad signature, got {other:?}"),
+        }
+
+        // checked once on mempool entry, then served from the cache
+        let transaction = spend(&coins[0], &alice);
+        let input = &transaction.inputs[0];
+        let cached = |blockchain: &Blockchain| {
+            blockchain.signature_cache.contains(
+                &input.prev_transaction_output_hash,
+                &input.signature,
+                &coins[0].pubkey,
+            )
+        };
+        assert!(!cached(&blockchain));
+        blockchain.add_to_mempool(transaction.clone()).unwrap();
+        assert!(cached(&blockchain));
+        let block = mine_block(&blockchain, alice.public_key(), vec![transaction.clone()]);
+        blockchain.add_block(block).unwrap();
+        assert!(blockchain.mempool().is_empty());
+        // spent, so it will not be seen again
+        assert!(!cached(&blockchain));
+    }
+
+    #[test]
+    fn fee_errors_name_the_transaction() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let block = mine_block(&blockchain, alice.public_key(), vec![]);
+        let output = block.transactions[0].outputs[0].clone();
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+
+        let mut overspend = spend(&output, &alice);
+        overspend.outputs[0].value = output.value.checked_add(Amount::from_sat(1)).unwrap();
+        let block = mine_block(&blockchain, alice.public_key(), vec![overspend.clone()]);
+        match block.calculate_miner_fees(blockchain.utxos()) {
+            Err(BtcError::Overspend { tx, .. }) => assert_eq!(tx, overspend.hash()),
+            other => panic!("expected overspend, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn spends_outputs_of_one_transaction_separately() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let block = mine_block(&blockchain, alice.public_key(), vec![]);
+        let coin = block.transactions[0].outputs[0].clone();
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+
+        // split the coin in two outputs of the same transaction
+        let mut split = spend(&coin, &alice);
+        let half = Amount::from_sat(coin.value.to_sat() / 2);
+        split.outputs = vec![
+            TransactionOutput {
+                value: half,
+                unique_id: Uuid::new_v4(),
+                pubkey: alice.public_key(),
+            },
+            TransactionOutput {
+                value: coin.value.checked_sub(half).unwrap(),
+                unique_id: Uuid::new_v4(),
+                pubkey: alice.public_key(),
+            },
+        ];
+        let block = mine_block(&blockchain, alice.public_key(), vec![split.clone()]);
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+        for output in &split.outputs {
+            assert!(blockchain.utxos().contains_key(&output.hash()));
+        }
+
+        let block = mine_block(
+            &blockchain,
+            alice.public_key(),
+            vec![spend(&split.outputs[1], &alice)],
+        );
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+        assert!(blockchain.utxos().contains_key(&split.outputs[0].hash()));
+        assert!(!blockchain.utxos().contains_key(&split.outputs[1].hash()));
+    }
+
+    #[test]
+    fn accepts_schnorr_spends() {
+        let alice = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..3 {
+            let block = mine_block(&blockchain, alice.x_only_public_key(), vec![]);
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain.rebuild_utxos();
+
+        let spends: Vec<Transaction> = blockchain
+            .blocks()
+            .skip(1)
+            .map(|block| spend(&block.transactions[0].outputs[0], &alice))
+            .collect();
+        let block = mine_block(&blockchain, alice.public_key(), spends);
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn serves_block_filters() {
+        let alice = PrivateKey::new_key();
+        let bob = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let block = mine_block(&blockchain, alice.public_key(), vec![]);
+        let output = block.transactions[0].outputs[0].clone();
+        blockchain.add_block(block).unwrap();
+        blockchain.rebuild_utxos();
+        let block = mine_block(&blockchain, bob.public_key(), vec![spend(&output, &alice)]);
+        blockchain.add_block(block).unwrap();
+
+        let filters = blockchain.filters_from(1);
+        assert_eq!(filters.len(), 2);
+        let (hash, filter) = &filters[0];
+        assert!(filter.matches_any(hash, &[alice.public_key()], &[]));
+        assert!(!filter.matches_any(hash, &[bob.public_key()], &[output.hash()]));
+        let (hash, filter) = &filters[1];
+        assert!(filter.matches_any(hash, &[], &[output.hash()]));
+
+        let headers = blockchain.filter_headers_from(0);
+        assert_eq!(headers[2], filters[1].1.header(&headers[1]));
+        let saved = blockchain.filter_headers.clone();
+        blockchain.rebuild_filters();
+        assert_eq!(blockchain.filter_headers, saved);
+    }
+}
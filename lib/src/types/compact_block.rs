@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+
+use super::{Block, BlockHeader, Transaction};
+use crate::encode::Encodable;
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use crate::util::MerkleRoot;
+
+/// BIP152-style block announcement: the header and a 6 byte id per
+/// transaction, which the receiver resolves against its mempool
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Salts the short ids so collisions differ per announcement
+    pub nonce: u64,
+    /// Short ids of the transactions that are not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    /// Transactions the receiver cannot have, with their block index;
+    /// always includes the coinbase
+    pub prefilled: Vec<(u32, Transaction)>,
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Self {
+        let mut compact = CompactBlock {
+            header: block.header.clone(),
+            nonce: rand::random(),
+            short_ids: vec![],
+            prefilled: vec![],
+        };
+        let hasher = compact.short_id_hasher();
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if index == 0 {
+                compact.prefilled.push((0, transaction.clone()));
+            } else {
+                compact
+                    .short_ids
+                    .push(short_id(hasher, &transaction.hash()));
+            }
+        }
+        compact
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// SipHash-2-4 of the transaction hash keyed by the header and nonce,
+    /// truncated to 48 bits
+    pub fn short_id(&self, transaction: &Hash) -> u64 {
+        short_id(self.short_id_hasher(), transaction)
+    }
+
+    fn short_id_hasher(&self) -> SipHasher24 {
+        let mut key = self.header.consensus_bytes();
+        self.nonce.consensus_encode(&mut key).unwrap();
+        let key = Hash::hash_bytes(&key).as_bytes();
+        SipHasher24::new_with_keys(
+            u64::from_le_bytes(key[0..8].try_into().unwrap()),
+            u64::from_le_bytes(key[8..16].try_into().unwrap()),
+        )
+    }
+
+    /// Fill in what the mempool has; an id matching several mempool
+    /// transactions is left missing rather than guessed. Prefilled indexes
+    /// have to be strictly increasing and inside the block.
+    pub fn reconstruct<'a>(
+        &self,
+        mempool: impl IntoIterator<Item = &'a Transaction>,
+    ) -> Result<PartialBlock> {
+        let mut slots: Vec<Option<Transaction>> = vec![None; self.transaction_count()];
+        let mut previous = None;
+        for (index, transaction) in &self.prefilled {
+            if previous.is_some_and(|previous| *index <= previous) || *index as usize >= slots.len()
+            {
+                return Err(BtcError::BadPrefilledIndex {
+                    block: self.header.hash(),
+                    index: *index,
+                });
+            }
+            slots[*index as usize] = Some(transaction.clone());
+            previous = Some(*index);
+        }
+
+        let hasher = self.short_id_hasher();
+        let mut candidates: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for transaction in mempool {
+            candidates
+                .entry(short_id(hasher, &transaction.hash()))
+                .and_modify(|candidate| *candidate = None)
+                .or_insert(Some(transaction));
+        }
+
+        let mut short_ids = self.short_ids.iter();
+        for slot in slots.iter_mut().filter(|slot| slot.is_none()) {
+            let Some(short_id) = short_ids.next() else {
+                break;
+            };
+            if let Some(Some(transaction)) = candidates.get(short_id) {
+                *slot = Some((*transaction).clone());
+            }
+        }
+
+        Ok(PartialBlock {
+            header: self.header.clone(),
+            slots,
+        })
+    }
+}
+
+fn short_id(mut hasher: SipHasher24, transaction: &Hash) -> u64 {
+    hasher.write(&transaction.as_bytes());
+    hasher.finish() & 0xffff_ffff_ffff
+}
+
+/// A block being rebuilt from a `CompactBlock`
+#[derive(Clone, Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    slots: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    pub fn block_hash(&self) -> Hash {
+        self.header.hash()
+    }
+
+    /// Indexes to ask for with `GetBlockTransactions`
+    pub fn missing(&self) -> Vec<u32> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Insert the answer to `GetBlockTransactions`, in the order of `missing`.
+    /// False if the count does not match.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return false;
+        }
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            self.slots[index as usize] = Some(transaction);
+        }
+        true
+    }
+
+    /// The full block, `None` while transactions are missing or if the
+    /// result does not match the merkle root (a short id collision); the
+    /// full block has to be fetched then
+    pub fn into_block(self) -> Option<Block> {
+        let transactions: Vec<Transaction> = self.slots.into_iter().collect::<Option<_>>()?;
+        match MerkleRoot::calculate(&transactions) {
+            Ok(root) if root == self.header.merkle_root => {
+                Some(Block::new(self.header, transactions))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::amount::Amount;
+    use crate::crypto::PrivateKey;
+    use crate::params::ChainParams;
+    use crate::types::TransactionOutput;
+
+    #[test]
+    fn reconstructs_from_mempool() {
+        let pubkey = PrivateKey::new_key().public_key();
+        let transactions: Vec<_> = (0..5)
+            .map(|_| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value: Amount::COIN,
+                        unique_id: Uuid::new_v4(),
+                        pubkey: pubkey.clone(),
+                    }],
+                )
+            })
+            .collect();
+        let mut header = ChainParams::regtest().genesis_block().header;
+        header.merkle_root = MerkleRoot::calculate(&transactions).unwrap();
+        let block = Block::new(header, transactions);
+
+        let compact = CompactBlock::new(&block);
+        // the mempool has all but the coinbase and the third transaction
+        let mempool = [
+            &block.transactions[1],
+            &block.transactions[3],
+            &block.transactions[4],
+        ];
+        let mut partial = compact.reconstruct(mempool).unwrap();
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(partial.clone().into_block().is_none());
+
+        assert!(!partial.fill(vec![]));
+        assert!(partial.fill(vec![block.transactions[2].clone()]));
+        assert_eq!(partial.into_block().unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn rejects_bad_prefilled_indexes() {
+        let block = ChainParams::regtest().genesis_block();
+        let mut compact = CompactBlock::new(&block);
+        let coinbase = compact.prefilled[0].1.clone();
+        compact.prefilled[0].0 = 1;
+        assert!(matches!(
+            compact.reconstruct([]),
+            Err(BtcError::BadPrefilledIndex { index: 1, .. })
+        ));
+
+        compact.prefilled = vec![(0, coinbase.clone()), (0, coinbase)];
+        compact.short_ids.clear();
+        assert!(matches!(
+            compact.reconstruct([]),
+            Err(BtcError::BadPrefilledIndex { index: 0, .. })
+        ));
+    }
+}
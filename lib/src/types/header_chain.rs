@@ -0,0 +1,153 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::difficulty::DifficultyAlgorithm;
+use crate::error::Result;
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::target;
+use crate::util::{MerkleProof, NetworkTime, Saveable};
+use crate::U256;
+
+use super::block::{locator_heights, median_timestamp};
+use super::BlockHeader;
+
+/// Most headers sent in one `Headers` message
+pub const MAX_HEADERS: usize = 2000;
+
+/// Header-only chain for light clients. Checks links, proof of work,
+/// retargeting and timestamps; transactions are only checked through
+/// merkle proofs handed out by an untrusted node.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HeaderChain {
+    headers: Vec<BlockHeader>,
+    target: U256,
+    params: ChainParams,
+    #[serde(skip)]
+    network_time: NetworkTime,
+}
+
+impl HeaderChain {
+    /// A chain holding only the genesis header of the network
+    pub fn new(params: ChainParams) -> Self {
+        HeaderChain {
+            headers: vec![params.genesis_block().header],
+            target: params.max_target(),
+            params,
+            network_time: NetworkTime::new(),
+        }
+    }
+
+    pub fn height(&self) -> u64 {
+        self.headers.len() as u64
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &BlockHeader> {
+        self.headers.iter()
+    }
+
+    pub fn header(&self, height: u64) -> Option<&BlockHeader> {
+        self.headers.get(height as usize)
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers
+            .last()
+            .expect("header chain starts with genesis")
+    }
+
+    pub fn target(&self) -> U256 {
+        self.target
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    pub fn network_time_mut(&mut self) -> &mut NetworkTime {
+        &mut self.network_time
+    }
+
+    /// Total expected hashes spent on the chain
+    pub fn chain_work(&self) -> U256 {
+        self.headers
+            .iter()
+            .fold(U256::zero(), |work, header| work + header.work())
+    }
+
+    /// Hashes to send in `GetHeaders`: the last ten headers, then
+    /// exponentially sparser back to genesis
+    pub fn locator(&self) -> Vec<Hash> {
+        locator_heights(self.headers.len())
+            .into_iter()
+            .map(|height| self.headers[height].hash())
+            .collect()
+    }
+
+    /// Median timestamp of the last `MEDIAN_TIME_PAST_WINDOW` headers
+    pub fn median_time_past(&self) -> DateTime<Utc> {
+        median_timestamp(&self.recent_timestamps()).expect("header chain starts with genesis")
+    }
+
+    fn recent_timestamps(&self) -> Vec<DateTime<Utc>> {
+        let window = self.headers.len().min(crate::MEDIAN_TIME_PAST_WINDOW);
+        self.headers[self.headers.len() - window..]
+            .iter()
+            .map(|header| header.timestamp)
+            .collect()
+    }
+
+    /// Same header checks as `Blockchain::validate_header`
+    pub fn add_header(&mut self, header: BlockHeader) -> Result<()> {
+        header.validate(
+            self.tip().hash(),
+            &self.recent_timestamps(),
+            self.target,
+            self.network_time.now(),
+        )?;
+        self.headers.push(header);
+        self.adjust_target();
+        Ok(())
+    }
+
+    /// Add headers in order, stopping at the first invalid one
+    pub fn add_headers(&mut self, headers: Vec<BlockHeader>) -> Result<()> {
+        for header in headers {
+            self.add_header(header)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `proof` shows a transaction in the block at `height`
+    pub fn verify_merkle_proof(&self, height: u64, proof: &MerkleProof) -> bool {
+        self.header(height)
+            .is_some_and(|header| proof.verify(header))
+    }
+
+    fn adjust_target(&mut self) {
+        let headers = &self.headers;
+        let new_target = self
+            .params
+            .difficulty
+            .next_target(self.height(), &|height| &headers[height as usize]);
+
+        self.target = target::normalise(new_target.clamp(U256::one(), self.params.max_target()))
+    }
+}
+
+impl Saveable for HeaderChain {
+    fn load<I: Read>(reader: I) -> IoResult<Self> {
+        ciborium::de::from_reader(reader).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidData,
+                "Failed to deserialize HeaderChain",
+            )
+        })
+    }
+    fn save<O: Write>(&self, writer: O) -> IoResult<()> {
+        ciborium::ser::into_writer(self, writer)
+            .map_err(|_| IoError::new(IoErrorKind::InvalidData, "Failed to serialize HeaderChain"))
+    }
+}
@@ -3,7 +3,9 @@ use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResu
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::amount::Amount;
 use crate::crypto::{PublicKey, Signature};
+use crate::encode::Encodable;
 use crate::sha256::Hash;
 use crate::util::Saveable;
 
@@ -19,7 +21,12 @@ impl Transaction {
     }
 
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.consensus_bytes())
+    }
+
+    /// Sum of all outputs, `None` if it exceeds `Amount::MAX_MONEY`
+    pub fn output_value(&self) -> Option<Amount> {
+        Amount::checked_sum(self.outputs.iter().map(|output| output.value))
     }
 }
 
@@ -48,13 +55,13 @@ pub struct TransactionInput {
 /// txo
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionOutput {
-    pub value: u64,
+    pub value: Amount,
     pub unique_id: Uuid,
     pub pubkey: PublicKey,
 }
 
 impl TransactionOutput {
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        Hash::hash_bytes(&self.consensus_bytes())
     }
 }
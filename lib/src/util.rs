@@ -1,32 +1,120 @@
+use std::fmt;
 use std::fs::File;
 use std::io::{Read, Result as IoResult, Write};
 use std::path::Path;
 
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::encode::Encodable;
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
-use crate::types::Transaction;
+use crate::types::{Block, BlockHeader, Transaction};
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
-pub struct MerkleRoot(Hash);
+pub struct MerkleRoot(pub(crate) Hash);
 
 impl MerkleRoot {
-    pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
-        let mut layer: Vec<Hash> = transactions.iter().map(|trx| Hash::hash(trx)).collect();
+    /// Fails on an empty list, and on a mutated tree: one where some level
+    /// pairs two equal hashes, so that a different transaction list (the last
+    /// ones repeated) has the same root (CVE-2012-2459).
+    pub fn calculate(transactions: &[Transaction]) -> Result<MerkleRoot> {
+        let mut layer: Vec<Hash> = transactions.iter().map(Transaction::hash).collect();
+        if layer.is_empty() {
+            return Err(BtcError::EmptyMerkleTree);
+        }
 
         while layer.len() > 1 {
-            let mut new_layer = vec![];
-
-            for pair in layer.chunks(2) {
-                let left = pair[0];
-                let right = pair.get(1).unwrap_or(&pair[0]);
-                new_layer.push(Hash::hash(&[left, *right]));
+            if layer
+                .chunks(2)
+                .any(|pair| pair.len() == 2 && pair[0] == pair[1])
+            {
+                return Err(BtcError::MutatedMerkleTree);
             }
+            layer = next_layer(&layer);
+        }
+
+        Ok(MerkleRoot(layer[0]))
+    }
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut bytes = left.consensus_bytes();
+    right.consensus_encode(&mut bytes).unwrap();
+    Hash::hash_bytes(&bytes)
+}
+
+// an odd last node is paired with itself
+fn next_layer(layer: &[Hash]) -> Vec<Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
 
-            layer = new_layer;
+/// Proof that a transaction is committed to by a block's merkle root,
+/// without the rest of the block
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// Hash of the proven transaction
+    pub transaction: Hash,
+    /// Position of the transaction in the block
+    pub index: u32,
+    /// Sibling hashes from the leaves up to the root
+    pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+    /// `None` if the block has no transaction at `index`
+    pub fn generate(block: &Block, index: usize) -> Option<MerkleProof> {
+        let transaction = block.transactions.get(index)?.hash();
+        let mut layer: Vec<Hash> = block.transactions.iter().map(Transaction::hash).collect();
+        let mut position = index;
+        let mut siblings = vec![];
+
+        while layer.len() > 1 {
+            let sibling = layer.get(position ^ 1).unwrap_or(&layer[position]);
+            siblings.push(*sibling);
+            layer = next_layer(&layer);
+            position /= 2;
         }
 
-        MerkleRoot(layer[0])
+        Some(MerkleProof {
+            transaction,
+            index: index as u32,
+            siblings,
+        })
+    }
+
+    /// Root implied by the transaction hash and the path, `None` if the path
+    /// pairs a right node with an equal left one: only an odd last node is
+    /// ever paired with itself, and it is always on the left. Also `None` if
+    /// the path is too long for a `u32` index or shorter than the index needs.
+    pub fn root(&self) -> Option<MerkleRoot> {
+        if self.siblings.len() >= 32 || self.index >> self.siblings.len() != 0 {
+            return None;
+        }
+        let mut hash = self.transaction;
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            hash = if (self.index >> level) & 1 == 0 {
+                hash_pair(&hash, sibling)
+            } else if *sibling != hash {
+                hash_pair(sibling, &hash)
+            } else {
+                return None;
+            };
+        }
+        Some(MerkleRoot(hash))
+    }
+
+    pub fn verify(&self, header: &BlockHeader) -> bool {
+        self.root() == Some(header.merkle_root)
+    }
+}
+
+impl fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -45,3 +133,121 @@ where
         Self::load(file)
     }
 }
+
+/// Local clock corrected by the median offset reported by peers
+#[derive(Clone, Debug, Default)]
+pub struct NetworkTime {
+    // peer clock minus local clock, in seconds
+    offsets: Vec<i64>,
+}
+
+impl NetworkTime {
+    // the first samples are enough, like bitcoind we do not keep listening forever
+    const MAX_SAMPLES: usize = 200;
+    const MIN_SAMPLES: usize = 5;
+
+    pub fn new() -> Self {
+        NetworkTime::default()
+    }
+
+    /// Record the time a peer reported when connecting
+    pub fn add_sample(&mut self, peer_time: DateTime<Utc>) {
+        if self.offsets.len() >= Self::MAX_SAMPLES {
+            return;
+        }
+        self.offsets.push((peer_time - Utc::now()).num_seconds());
+    }
+
+    pub fn offset(&self) -> Duration {
+        if self.offsets.len() < Self::MIN_SAMPLES {
+            return Duration::zero();
+        }
+
+        let mut offsets = self.offsets.clone();
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+
+        // a large disagreement means our own clock (or the peers) is broken,
+        // trust the local clock rather than follow the peers
+        if median.unsigned_abs() > crate::MAX_PEER_TIME_OFFSET {
+            return Duration::zero();
+        }
+        Duration::seconds(median)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.offset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::crypto::PrivateKey;
+    use crate::types::TransactionOutput;
+    use uuid::Uuid;
+
+    fn block_with(count: usize) -> Block {
+        let pubkey = PrivateKey::new_key().public_key();
+        let transactions: Vec<_> = (0..count)
+            .map(|_| {
+                Transaction::new(
+                    vec![],
+                    vec![TransactionOutput {
+                        value: Amount::COIN,
+                        unique_id: Uuid::new_v4(),
+                        pubkey: pubkey.clone(),
+                    }],
+                )
+            })
+            .collect();
+        let mut header = crate::params::ChainParams::regtest().genesis_block().header;
+        header.merkle_root = MerkleRoot::calculate(&transactions).unwrap();
+        Block::new(header, transactions)
+    }
+
+    #[test]
+    fn merkle_proofs() {
+        for count in 1..=7 {
+            let block = block_with(count);
+            for index in 0..count {
+                let proof = MerkleProof::generate(&block, index).unwrap();
+                assert!(proof.verify(&block.header), "{index} of {count}");
+
+                let mut wrong_index = proof.clone();
+                wrong_index.index ^= 1;
+                assert!(count == 1 || !wrong_index.verify(&block.header));
+            }
+            assert!(MerkleProof::generate(&block, count).is_none());
+        }
+
+        let block = block_with(4);
+        let mut proof = MerkleProof::generate(&block, 2).unwrap();
+        proof.transaction = block.transactions[1].hash();
+        assert!(!proof.verify(&block.header));
+
+        // as a peer could send it: too many siblings for the index
+        let mut proof = MerkleProof::generate(&block, 2).unwrap();
+        proof.siblings = vec![proof.transaction; 40];
+        assert!(proof.root().is_none());
+        proof.siblings.truncate(1);
+        assert!(proof.root().is_none());
+    }
+
+    #[test]
+    fn rejects_mutated_trees() {
+        assert!(matches!(
+            MerkleRoot::calculate(&[]),
+            Err(BtcError::EmptyMerkleTree)
+        ));
+
+        let block = block_with(3);
+        let mut transactions = block.transactions.clone();
+        transactions.push(transactions[2].clone());
+        assert!(matches!(
+            MerkleRoot::calculate(&transactions),
+            Err(BtcError::MutatedMerkleTree)
+        ));
+    }
+}
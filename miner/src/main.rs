@@ -1,21 +1,27 @@
+use std::env;
+use std::io::{Read, Result as IoResult, Write};
+use std::path::Path;
 use std::process::exit;
-use std::{env, usize};
 
 use btclib::crypto::PublicKey;
+use btclib::network::Message;
+use btclib::proxy::{self, Socks5Proxy};
+use btclib::transport::{self, Connection, Encryption, TransportConfig};
 use btclib::types::Block;
 use btclib::util::Saveable;
 
-use tokio::net::TcpStream;
+// nonces tried between checks that the template is still current
+const STEPS: usize = 1_000_000;
+
 fn usage() -> ! {
     eprintln!(
-        "Usage: {} <address> <public_key_file>",
+        "Usage: {} <address> <public_key_file> [--proxy <socks5://host:port>] [--encryption disabled|preferred|required] [--identity <keyfile>] [--pin <public_key_file>]",
         env::args().next().unwrap()
     );
     exit(1);
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let address = match env::args().nth(1) {
         Some(address) => address,
         None => usage(),
@@ -24,43 +30,239 @@ async fn main() {
         Some(pkf) => pkf,
         None => usage(),
     };
+    let mut proxy: Option<Socks5Proxy> = None;
+    let mut encryption = Encryption::default();
+    let mut identity = None;
+    let mut pinned_peer = None;
+    let mut args = env::args().skip(3);
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else { usage() };
+        match flag.as_str() {
+            "--proxy" => match value.parse() {
+                Ok(value) => proxy = Some(value),
+                Err(e) => {
+                    eprintln!("Invalid proxy: {e}");
+                    exit(1);
+                }
+            },
+            "--encryption" => match value.parse() {
+                Ok(value) => encryption = value,
+                Err(e) => {
+                    eprintln!("Invalid encryption policy: {e}");
+                    exit(1);
+                }
+            },
+            "--identity" => match transport::load_identity(Path::new(&value)) {
+                Ok(value) => identity = Some(value),
+                Err(e) => {
+                    eprintln!("Failed to load the identity from {value}: {e}");
+                    exit(1);
+                }
+            },
+            "--pin" => match PublicKey::load_from_file(&value) {
+                Ok(value) => pinned_peer = Some(value),
+                Err(e) => {
+                    eprintln!("Failed to read the pinned key from {value}: {e}");
+                    exit(1);
+                }
+            },
+            _ => usage(),
+        }
+    }
 
-    if let Ok(publick_key) = PublicKey::load_from_file(&public_key_file) {
-        println!("Connection to {address} to mine with {publick_key:?}");
-    } else {
+    let Ok(pubkey) = PublicKey::load_from_file(&public_key_file) else {
         eprintln!("Error reading public key from file {}", public_key_file);
         exit(1);
     };
+    let transport = TransportConfig {
+        encryption,
+        identity,
+        pinned_peer,
+    };
+    let connection = proxy::connect(&address, proxy.as_ref())
+        .and_then(|stream| Connection::connect(stream, &transport));
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to {address}: {e}");
+            exit(1);
+        }
+    };
+    let encrypted = if connection.is_encrypted() {
+        "encrypted"
+    } else {
+        "plaintext"
+    };
+    println!("Connected to {address} ({encrypted}), mining to {public_key_file}");
+
+    loop {
+        match mine(&mut connection, &pubkey) {
+            Ok(block) => println!("Mined block {}", block.hash()),
+            Err(e) => {
+                eprintln!("Lost connection to {address}: {e}");
+                exit(1);
+            }
+        }
+    }
+}
+
+/// Mine on templates from the node until one of our blocks is accepted
+fn mine<S: Read + Write>(connection: &mut Connection<S>, pubkey: &PublicKey) -> IoResult<Block> {
+    loop {
+        connection.send(&Message::FetchTemplate(pubkey.clone()))?;
+        let mut block = wait_for(connection, |message| match message {
+            Message::Template(block) => Some(block),
+            _ => None,
+        })?;
+        loop {
+            if block.header.mine(STEPS) {
+                connection.send(&Message::SubmitTemplate(block.clone()))?;
+                if wait_for(connection, validity)? {
+                    return Ok(block);
+                }
+                // another block got there first
+                break;
+            }
+            connection.send(&Message::ValidateTemplate(block.clone()))?;
+            if !wait_for(connection, validity)? {
+                break;
+            }
+        }
+    }
+}
+
+fn validity(message: Message) -> Option<bool> {
+    match message {
+        Message::TemplateValidity(valid) => Some(valid),
+        _ => None,
+    }
+}
+
+// answers pings while waiting; announcements meant for full nodes are skipped
+fn wait_for<S: Read + Write, T>(
+    connection: &mut Connection<S>,
+    wanted: impl Fn(Message) -> Option<T>,
+) -> IoResult<T> {
+    loop {
+        match connection.receive()? {
+            Message::Ping(nonce) => connection.send(&Message::Pong(nonce))?,
+            message => {
+                if let Some(value) = wanted(message) {
+                    return Ok(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::copy;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+
+    use btclib::crypto::PrivateKey;
+    use btclib::node::{Action, Node};
+    use btclib::params::ChainParams;
+    use btclib::types::Blockchain;
+
+    use super::*;
+
+    fn required() -> TransportConfig {
+        TransportConfig {
+            encryption: Encryption::Required,
+            ..Default::default()
+        }
+    }
+
+    // a full node serving its peers one at a time, encrypted only
+    fn node_server(node: Arc<Mutex<Node>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let deliver = |node: &mut Node, connection: &mut Connection<TcpStream>| {
+                for action in node.take_actions() {
+                    if let Action::Send(_, message) = action {
+                        connection.send(&message).unwrap();
+                    }
+                }
+            };
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let peer = stream.peer_addr().unwrap();
+                let mut connection = Connection::accept(stream, &required()).unwrap();
+                let mut locked = node.lock().unwrap();
+                locked.connected(peer, false, Instant::now());
+                deliver(&mut locked, &mut connection);
+                drop(locked);
+                while let Ok(message) = connection.receive() {
+                    let mut node = node.lock().unwrap();
+                    node.receive(peer, message, Instant::now());
+                    deliver(&mut node, &mut connection);
+                }
+            }
+        });
+        address
+    }
+
+    // SOCKS5 without authentication that connects wherever it is asked and
+    // relays both ways; reports the requested host:port
+    fn socks5_proxy() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (targets, requested) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).unwrap();
+            client.write_all(&[5, 0]).unwrap();
+            // version, connect, reserved, domain name, length
+            let mut request = [0u8; 5];
+            client.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[5, 1, 0, 3]);
+            let mut host = vec![0u8; request[4] as usize];
+            client.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            client.read_exact(&mut port).unwrap();
+            let target = format!(
+                "{}:{}",
+                String::from_utf8(host).unwrap(),
+                u16::from_be_bytes(port)
+            );
+            let mut upstream = TcpStream::connect(&target).unwrap();
+            client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            targets.send(target).unwrap();
+
+            let (mut client_reader, mut upstream_reader) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || copy(&mut client_reader, &mut upstream));
+            let _ = copy(&mut upstream_reader, &mut client);
+        });
+        (address, requested)
+    }
+
+    #[test]
+    fn mines_through_proxy() {
+        let node = Arc::new(Mutex::new(Node::new(Blockchain::new(
+            ChainParams::regtest(),
+        ))));
+        let node_address = node_server(node.clone());
+        let (proxy_address, requested) = socks5_proxy();
+        let proxy: Socks5Proxy = proxy_address.parse().unwrap();
+
+        // the proxy resolves the name, not the miner
+        let target = format!("localhost:{}", node_address.port());
+        let stream = proxy::connect(&target, Some(&proxy)).unwrap();
+        let mut connection = Connection::connect(stream, &required()).unwrap();
+        assert!(connection.is_encrypted());
+        let pubkey = PrivateKey::new_key().public_key();
+        for _ in 0..2 {
+            let block = mine(&mut connection, &pubkey).unwrap();
+            assert_eq!(block.transactions[0].outputs[0].pubkey, pubkey);
+        }
+        assert_eq!(requested.recv().unwrap(), target);
+        assert_eq!(node.lock().unwrap().blockchain().block_height(), 3);
+    }
 }
-// async fn main() {
-//     let (path, steps) = if let (Some(arg), Some(arg2)) = (env::args().nth(1), env::args().nth(2)) {
-//         (arg, arg2)
-//     } else {
-//         eprintln!("Usage: miner <block_file> <steps>");
-//         exit(1)
-//     };
-
-//     let steps: usize = if let Ok(s @ 1..=usize::MAX) = steps.parse() {
-//         s
-//     } else {
-//         eprintln!("invalid <steps> value");
-//         exit(1)
-//     };
-
-//     let original_block = Block::load_from_file(path).expect("failed load block");
-//     let mut block = original_block.clone();
-
-//     println!(
-//         "original block header hash: {}",
-//         original_block.header.hash()
-//     );
-
-//     while !block.header.mine(steps) {
-//         println!("mining")
-//     }
-
-//     println!(
-//         "mined block header hash: {}",
-//         format!("{:064x}", block.header.hash()),
-//     );
-// }
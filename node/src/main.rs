@@ -1,3 +1,377 @@
+mod p2p;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use btclib::crypto::PublicKey;
+use btclib::explorer;
+use btclib::http::{Request, Response};
+use btclib::index::ChainIndex;
+use btclib::node::Node;
+use btclib::params::ChainParams;
+use btclib::peer::BanList;
+use btclib::proxy::Socks5Proxy;
+use btclib::rpc::{self, RpcAuth};
+use btclib::transport::{self, Encryption, TransportConfig};
+use btclib::types::Blockchain;
+use btclib::util::Saveable;
+
+const DEFAULT_RPC_BIND: &str = "127.0.0.1:9332";
+const DEFAULT_P2P_BIND: &str = "127.0.0.1:9333";
+// next to the chain
+const BANS_FILE: &str = "bans.toml";
+// HTTP connections served at once, per listener
+const MAX_HTTP_CONNECTIONS: usize = 32;
+// for a request to arrive and a response to be taken
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Config {
+    params: ChainParams,
+    blockchain: Option<PathBuf>,
+    rpc_bind: String,
+    rpc_user: Option<String>,
+    rpc_password: Option<String>,
+    explorer_bind: Option<String>,
+    p2p_bind: String,
+    connect: Vec<String>,
+    // for the peers we connect to
+    proxy: Option<Socks5Proxy>,
+    encryption: Encryption,
+    identity: Option<PathBuf>,
+    // only applies to the peers we connect to
+    pin: Option<PathBuf>,
+}
+
+pub struct State {
+    node: Node,
+    // caught up lazily by the explorer
+    index: ChainIndex,
+    path: Option<PathBuf>,
+    // height last written to `path`
+    saved_height: u64,
+    bans_path: Option<PathBuf>,
+    saved_bans: BanList,
+    links: HashMap<SocketAddr, p2p::Link>,
+}
+
+impl State {
+    /// Carry out the node's actions, save the chain if it grew and the
+    /// bans if they changed
+    fn flush(&mut self) {
+        p2p::send_actions(self);
+        let height = self.node.blockchain().block_height();
+        if height != self.saved_height {
+            self.saved_height = height;
+            if let Some(path) = &self.path {
+                if let Err(e) = self.node.blockchain().save_to_file(path) {
+                    eprintln!("Failed to save blockchain to {}: {e}", path.display());
+                }
+            }
+        }
+        if *self.node.bans() != self.saved_bans {
+            self.saved_bans = self.node.bans().clone();
+            if let Some(path) = &self.bans_path {
+                if let Err(e) = self.saved_bans.save_to_file(path) {
+                    eprintln!("Failed to save bans to {}: {e}", path.display());
+                }
+            }
+        }
+    }
+}
+
+pub fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().expect("node state lock poisoned")
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: {} [--network <name|params.toml>] [--blockchain <file>] [--rpc-bind <host:port>] [--rpc-user <user> --rpc-password <password>] [--explorer-bind <host:port>] [--p2p-bind <host:port>] [--connect <host:port>]... [--proxy <socks5://[user:password@]host:port>] [--encryption disabled|preferred|required] [--identity <keyfile>] [--pin <public_key_file>]",
+        env::args().next().unwrap()
+    );
+    exit(1);
+}
+
+fn parse_args() -> Config {
+    let mut config = Config {
+        params: ChainParams::default(),
+        blockchain: None,
+        rpc_bind: DEFAULT_RPC_BIND.to_string(),
+        rpc_user: None,
+        rpc_password: None,
+        explorer_bind: None,
+        p2p_bind: DEFAULT_P2P_BIND.to_string(),
+        connect: vec![],
+        proxy: None,
+        encryption: Encryption::default(),
+        identity: None,
+        pin: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else { usage() };
+        match flag.as_str() {
+            "--network" => match ChainParams::from_arg(&value) {
+                Ok(params) => config.params = params,
+                Err(e) => {
+                    eprintln!("Invalid network {value}: {e}");
+                    exit(1);
+                }
+            },
+            "--blockchain" => config.blockchain = Some(PathBuf::from(value)),
+            "--rpc-bind" => config.rpc_bind = value,
+            "--rpc-user" => config.rpc_user = Some(value),
+            "--rpc-password" => config.rpc_password = Some(value),
+            "--explorer-bind" => config.explorer_bind = Some(value),
+            "--p2p-bind" => config.p2p_bind = value,
+            "--connect" => config.connect.push(value),
+            "--proxy" => match value.parse() {
+                Ok(proxy) => config.proxy = Some(proxy),
+                Err(e) => {
+                    eprintln!("Invalid proxy: {e}");
+                    exit(1);
+                }
+            },
+            "--encryption" => match value.parse() {
+                Ok(encryption) => config.encryption = encryption,
+                Err(e) => {
+                    eprintln!("Invalid encryption policy: {e}");
+                    exit(1);
+                }
+            },
+            "--identity" => config.identity = Some(PathBuf::from(value)),
+            "--pin" => config.pin = Some(PathBuf::from(value)),
+            _ => usage(),
+        }
+    }
+    config
+}
+
 fn main() {
-    println!("Hello, world!");
+    let config = parse_args();
+
+    let blockchain = match &config.blockchain {
+        Some(path) if path.exists() => match Blockchain::load_from_file(path) {
+            Ok(mut blockchain) => {
+                // files saved by older versions may hold stale outputs
+                // and no filters
+                blockchain.rebuild_utxos();
+                blockchain.rebuild_filters();
+                blockchain
+            }
+            Err(e) => {
+                eprintln!("Failed to load blockchain from {}: {e}", path.display());
+                exit(1);
+            }
+        },
+        _ => Blockchain::new(config.params.clone()),
+    };
+    println!(
+        "{} chain at height {}",
+        blockchain.params().network,
+        blockchain.block_height() - 1
+    );
+
+    let data_dir = config
+        .blockchain
+        .as_deref()
+        .and_then(Path::parent)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    // kept only alongside a saved chain
+    let bans_path = config.blockchain.as_ref().map(|_| data_dir.join(BANS_FILE));
+    let bans = match &bans_path {
+        Some(path) if path.exists() => match BanList::load_from_file(path) {
+            Ok(bans) => bans,
+            Err(e) => {
+                eprintln!("Failed to load bans from {}: {e}", path.display());
+                exit(1);
+            }
+        },
+        _ => BanList::new(),
+    };
+
+    // password auth if configured, a cookie next to the chain otherwise
+    let mut cookie_dir = None;
+    let auth = match (config.rpc_user, config.rpc_password) {
+        (Some(user), Some(password)) => RpcAuth::new(&user, &password),
+        (None, None) => {
+            let dir = data_dir.as_path();
+            match RpcAuth::cookie(dir) {
+                Ok(auth) => {
+                    cookie_dir = Some(dir.to_path_buf());
+                    auth
+                }
+                Err(e) => {
+                    eprintln!("Failed to write the RPC cookie to {}: {e}", dir.display());
+                    exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("--rpc-user and --rpc-password go together");
+            exit(1);
+        }
+    };
+
+    // a stable identity lets peers pin this node
+    let identity = config
+        .identity
+        .as_deref()
+        .map(|path| match transport::load_identity(path) {
+            Ok(identity) => identity,
+            Err(e) => {
+                eprintln!("Failed to load the identity from {}: {e}", path.display());
+                exit(1);
+            }
+        });
+    let pinned_peer = config
+        .pin
+        .as_deref()
+        .map(|path| match PublicKey::load_from_file(path) {
+            Ok(key) => key,
+            Err(e) => {
+                eprintln!("Failed to read the pinned key from {}: {e}", path.display());
+                exit(1);
+            }
+        });
+
+    let rpc_listener = bind(&config.rpc_bind, "JSON-RPC server");
+    let p2p_listener = bind(&config.p2p_bind, "Peer-to-peer server");
+    let explorer_listener = config
+        .explorer_bind
+        .as_deref()
+        .map(|address| bind(address, "Block explorer"));
+
+    let mut node = Node::new(blockchain);
+    *node.bans_mut() = bans.clone();
+    let state = Arc::new(Mutex::new(State {
+        index: ChainIndex::new(node.blockchain()),
+        saved_height: node.blockchain().block_height(),
+        node,
+        path: config.blockchain,
+        bans_path,
+        saved_bans: bans,
+        links: HashMap::new(),
+    }));
+    {
+        let state = state.clone();
+        let handler = move || {
+            // wait for a save in progress to finish
+            let _state = state.lock();
+            if let Some(dir) = &cookie_dir {
+                if let Err(e) = RpcAuth::remove_cookie(dir) {
+                    eprintln!("Failed to remove the RPC cookie: {e}");
+                }
+            }
+            exit(0);
+        };
+        if let Err(e) = ctrlc::set_handler(handler) {
+            eprintln!("Failed to install the shutdown handler: {e}");
+            exit(1);
+        }
+    }
+    if let Some(listener) = explorer_listener {
+        let state = state.clone();
+        thread::spawn(move || {
+            listen(listener, state, |state, request| {
+                state.index.update(state.node.blockchain());
+                explorer::respond(state.node.blockchain(), &state.index, request)
+            })
+        });
+    }
+    let transport = Arc::new(TransportConfig {
+        encryption: config.encryption,
+        identity,
+        pinned_peer: None,
+    });
+    // inbound peers such as miners can be anyone, the pin only guards the
+    // peers we connect to
+    let outbound = Arc::new(TransportConfig {
+        pinned_peer,
+        ..(*transport).clone()
+    });
+    {
+        let (state, transport) = (state.clone(), transport.clone());
+        thread::spawn(move || p2p::listen(p2p_listener, state, transport));
+    }
+    {
+        let state = state.clone();
+        thread::spawn(move || p2p::tick(state));
+    }
+    for address in config.connect {
+        p2p::connect(
+            address,
+            state.clone(),
+            outbound.clone(),
+            config.proxy.clone(),
+        );
+    }
+    let auth = Arc::new(auth);
+    listen(rpc_listener, state, move |state, request| {
+        let response = rpc::respond(&auth, &mut state.node, request);
+        state.flush();
+        response
+    });
+}
+
+fn bind(address: &str, name: &str) -> TcpListener {
+    match TcpListener::bind(address) {
+        Ok(listener) => {
+            println!("{name} listening on {address}");
+            listener
+        }
+        Err(e) => {
+            eprintln!("Failed to bind {name} to {address}: {e}");
+            exit(1);
+        }
+    }
+}
+
+/// Serve one HTTP request per connection, each on its own thread, at
+/// most `MAX_HTTP_CONNECTIONS` at once
+fn listen<F>(listener: TcpListener, state: Arc<Mutex<State>>, handler: F)
+where
+    F: Fn(&mut State, &Request) -> Response + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_HTTP_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.set_write_timeout(Some(HTTP_TIMEOUT));
+            let _ = Response::text(503, "too many connections").write(&mut stream);
+            continue;
+        }
+        let (state, handler, connections) = (state.clone(), handler.clone(), connections.clone());
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, &state, handler.as_ref()) {
+                eprintln!("HTTP connection failed: {e}");
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn serve<F>(mut stream: TcpStream, state: &Mutex<State>, handler: &F) -> std::io::Result<()>
+where
+    F: Fn(&mut State, &Request) -> Response,
+{
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let request = match Request::read(&mut BufReader::new(&stream)) {
+        Ok(request) => request,
+        Err(e) => return Response::text(400, &e.to_string()).write(&mut stream),
+    };
+    let response = handler(&mut lock(state), &request);
+    response.write(&mut stream)
 }
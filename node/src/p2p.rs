@@ -0,0 +1,301 @@
+//! Peer connections: a reader thread per peer feeds received messages to
+//! the `Node`, a writer thread per peer sends what it queues.
+
+use std::io::{ErrorKind, Result as IoResult};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use btclib::network::Message;
+use btclib::node::Action;
+use btclib::proxy::{self, Socks5Proxy};
+use btclib::transport::{Connection, TransportConfig};
+
+use crate::{lock, State};
+
+/// Connected peers, inbound and outbound together
+const MAX_PEERS: usize = 64;
+// a peer that does not take our messages for this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+// for the transport handshake, before the peer counts as connected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// how often peers are checked for pings and timeouts
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where messages for a connected peer go
+pub struct Link {
+    sender: mpsc::Sender<Message>,
+}
+
+/// Hand what the node queued to the writer threads
+pub fn send_actions(state: &mut State) {
+    for action in state.node.take_actions() {
+        match action {
+            Action::Send(address, message) => {
+                if let Some(link) = state.links.get(&address) {
+                    // a closed channel means the peer is going away
+                    let _ = link.sender.send(message);
+                }
+            }
+            // the writer drains the queue, then closes the socket
+            Action::Disconnect(address) => {
+                state.links.remove(&address);
+            }
+        }
+    }
+}
+
+/// Accept peers until the listener fails
+pub fn listen(listener: TcpListener, state: Arc<Mutex<State>>, transport: Arc<TransportConfig>) {
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else { continue };
+        if lock(&state).links.len() >= MAX_PEERS {
+            continue;
+        }
+        let Ok(address) = stream.peer_addr() else {
+            continue;
+        };
+        let (state, transport) = (state.clone(), transport.clone());
+        thread::spawn(move || run(&state, &transport, stream, address, false));
+    }
+}
+
+/// Keep pinging peers, forever
+pub fn tick(state: Arc<Mutex<State>>) {
+    loop {
+        thread::sleep(TICK_INTERVAL);
+        let mut state = lock(&state);
+        state.node.tick(Instant::now());
+        state.flush();
+    }
+}
+
+/// Open a connection to `target` on a new thread, through `proxy` if set
+pub fn connect(
+    target: String,
+    state: Arc<Mutex<State>>,
+    transport: Arc<TransportConfig>,
+    proxy: Option<Socks5Proxy>,
+) {
+    thread::spawn(move || {
+        let connected = proxy::connect(&target, proxy.as_ref()).and_then(|stream| {
+            let address = peer_address(&target, &stream, proxy.is_some())?;
+            Ok((stream, address))
+        });
+        match connected {
+            Ok((stream, address)) => run(&state, &transport, stream, address, true),
+            Err(e) => eprintln!("Failed to connect to {target}: {e}"),
+        }
+    });
+}
+
+// through a proxy the socket's peer is the proxy, shared by every peer
+// behind it; a name only the proxy resolves keeps the proxy's IP and takes
+// our end's port, which no other connection has
+fn peer_address(target: &str, stream: &TcpStream, proxied: bool) -> IoResult<SocketAddr> {
+    if !proxied {
+        return stream.peer_addr();
+    }
+    if let Ok(address) = target.parse() {
+        return Ok(address);
+    }
+    Ok(SocketAddr::new(
+        stream.peer_addr()?.ip(),
+        stream.local_addr()?.port(),
+    ))
+}
+
+fn run(
+    state: &Mutex<State>,
+    transport: &TransportConfig,
+    stream: TcpStream,
+    address: SocketAddr,
+    outbound: bool,
+) {
+    if let Err(e) = serve(state, transport, stream, address, outbound) {
+        eprintln!("Peer {address} disconnected: {e}");
+    }
+    let mut state = lock(state);
+    state.links.remove(&address);
+    state.node.disconnected(address);
+    state.flush();
+}
+
+fn serve(
+    state: &Mutex<State>,
+    transport: &TransportConfig,
+    stream: TcpStream,
+    address: SocketAddr,
+    outbound: bool,
+) -> IoResult<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let writer = stream.try_clone()?;
+    let connection = match outbound {
+        true => Connection::connect(stream, transport)?,
+        false => Connection::accept(stream, transport)?,
+    };
+    // from here on keepalive notices silent peers
+    connection.get_ref().set_read_timeout(None)?;
+    let (mut reader, mut writer) = connection.split(writer);
+
+    let (sender, receiver) = mpsc::channel::<Message>();
+    // ends once the link is removed and the queue drained, and takes the
+    // reader down with it
+    thread::spawn(move || {
+        for message in receiver {
+            if writer.send(&message).is_err() {
+                break;
+            }
+        }
+        let _ = writer.get_ref().shutdown(Shutdown::Both);
+    });
+
+    {
+        let mut state = lock(state);
+        state.links.insert(address, Link { sender });
+        state.node.connected(address, outbound, Instant::now());
+        state.flush();
+    }
+
+    loop {
+        let message = reader.receive();
+        let mut state = lock(state);
+        match message {
+            Ok(message) => state.node.receive(address, message, Instant::now()),
+            // scored, so a peer that keeps sending garbage ends up banned
+            // and disconnected by the node
+            Err(e) if e.kind() == ErrorKind::InvalidData => state.node.malformed(address),
+            Err(e) => return Err(e),
+        }
+        state.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{copy, Read, Write};
+
+    use btclib::index::ChainIndex;
+    use btclib::node::Node;
+    use btclib::params::ChainParams;
+    use btclib::peer::BanList;
+    use btclib::types::Blockchain;
+
+    use super::*;
+
+    fn state() -> Arc<Mutex<State>> {
+        let node = Node::new(Blockchain::new(ChainParams::regtest()));
+        Arc::new(Mutex::new(State {
+            index: ChainIndex::new(node.blockchain()),
+            saved_height: node.blockchain().block_height(),
+            node,
+            path: None,
+            bans_path: None,
+            saved_bans: BanList::new(),
+            links: HashMap::new(),
+        }))
+    }
+
+    // a node listening for peers on a free port
+    fn listening(state: &Arc<Mutex<State>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = state.clone();
+        let transport = Arc::new(TransportConfig::default());
+        thread::spawn(move || listen(listener, state, transport));
+        address
+    }
+
+    // SOCKS5 without authentication that connects wherever it is asked and
+    // relays both ways; reports the requested host:port
+    fn socks5_proxy() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (targets, requested) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).unwrap();
+            client.write_all(&[5, 0]).unwrap();
+            // version, connect, reserved, domain name, length
+            let mut request = [0u8; 5];
+            client.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[5, 1, 0, 3]);
+            let mut host = vec![0u8; request[4] as usize];
+            client.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            client.read_exact(&mut port).unwrap();
+            let target = format!(
+                "{}:{}",
+                String::from_utf8(host).unwrap(),
+                u16::from_be_bytes(port)
+            );
+            let mut upstream = TcpStream::connect(&target).unwrap();
+            client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+            targets.send(target).unwrap();
+
+            let (mut client_reader, mut upstream_reader) =
+                (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || copy(&mut client_reader, &mut upstream));
+            let _ = copy(&mut upstream_reader, &mut client);
+        });
+        (address, requested)
+    }
+
+    #[test]
+    fn connects_through_proxy() {
+        let remote = state();
+        let remote_address = listening(&remote);
+        let (proxy_address, requested) = socks5_proxy();
+
+        // the proxy resolves the name, not the node
+        let target = format!("localhost:{}", remote_address.port());
+        let local = state();
+        connect(
+            target.clone(),
+            local.clone(),
+            Arc::new(TransportConfig::default()),
+            Some(proxy_address.parse().unwrap()),
+        );
+        assert_eq!(requested.recv_timeout(HANDSHAKE_TIMEOUT).unwrap(), target);
+
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        while lock(&remote).node.peer_info().is_empty() || lock(&local).links.is_empty() {
+            assert!(Instant::now() < deadline, "peers never connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // keyed apart from the proxy itself
+        let proxy_address: SocketAddr = proxy_address.parse().unwrap();
+        let local = lock(&local);
+        let (peer, _) = local.links.iter().next().unwrap();
+        assert_eq!(peer.ip(), proxy_address.ip());
+        assert_ne!(peer.port(), proxy_address.port());
+    }
+
+    #[test]
+    fn bans_peers_sending_junk() {
+        let state = state();
+        let address = listening(&state);
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).unwrap();
+        let connection = Connection::connect(stream, &TransportConfig::default()).unwrap();
+        let mut stream = connection.get_ref().try_clone().unwrap();
+        // well framed, but 0xff never starts a CBOR value
+        for _ in 0..10 {
+            stream.write_all(&4u64.to_be_bytes()).unwrap();
+            stream.write_all(&[0xff; 4]).unwrap();
+        }
+        // the node hangs up once the ban is in place
+        let mut rest = vec![];
+        let _ = stream.read_to_end(&mut rest);
+
+        let ip = address.ip();
+        assert!(lock(&state).node.bans().is_banned(&ip));
+        assert!(lock(&state).links.is_empty());
+    }
+}
@@ -1,3 +1,429 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Read, Result as IoResult, Write};
+use std::process::exit;
+
+use btclib::amount::Amount;
+use btclib::crypto::PublicKey;
+use btclib::network::Message;
+use btclib::params::ChainParams;
+use btclib::proxy::{self, Socks5Proxy};
+use btclib::sha256::Hash;
+use btclib::transport::{Connection, Encryption, TransportConfig};
+use btclib::types::{Block, HeaderChain, TransactionOutput};
+use btclib::util::{MerkleRoot, Saveable};
+
+struct Config {
+    address: String,
+    public_key_file: String,
+    // headers and filters only, nothing the node says is taken on trust
+    light: bool,
+    params: ChainParams,
+    proxy: Option<Socks5Proxy>,
+    encryption: Encryption,
+    // transactions to prove in light mode
+    confirm: Vec<Hash>,
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: {} <address> <public_key_file> [--light] [--network <name|params.toml>] [--proxy <socks5://[user:password@]host:port>] [--encryption disabled|preferred|required] [--confirm <txid>]...",
+        env::args().next().unwrap()
+    );
+    exit(1);
+}
+
+fn parse_args() -> Config {
+    let mut args = env::args().skip(1);
+    let (Some(address), Some(public_key_file)) = (args.next(), args.next()) else {
+        usage()
+    };
+    let mut config = Config {
+        address,
+        public_key_file,
+        light: false,
+        params: ChainParams::default(),
+        proxy: None,
+        encryption: Encryption::default(),
+        confirm: vec![],
+    };
+    while let Some(flag) = args.next() {
+        if flag == "--light" {
+            config.light = true;
+            continue;
+        }
+        let Some(value) = args.next() else { usage() };
+        match flag.as_str() {
+            "--network" => match ChainParams::from_arg(&value) {
+                Ok(params) => config.params = params,
+                Err(e) => {
+                    eprintln!("Invalid network {value}: {e}");
+                    exit(1);
+                }
+            },
+            "--proxy" => match value.parse() {
+                Ok(value) => config.proxy = Some(value),
+                Err(e) => {
+                    eprintln!("Invalid proxy: {e}");
+                    exit(1);
+                }
+            },
+            "--encryption" => match value.parse() {
+                Ok(value) => config.encryption = value,
+                Err(e) => {
+                    eprintln!("Invalid encryption policy: {e}");
+                    exit(1);
+                }
+            },
+            "--confirm" => match value.parse() {
+                Ok(txid) => config.confirm.push(txid),
+                Err(e) => {
+                    eprintln!("Invalid transaction id {value}: {e}");
+                    exit(1);
+                }
+            },
+            _ => usage(),
+        }
+    }
+    config
+}
+
 fn main() {
-    println!("Hello, world!");
+    let config = parse_args();
+    let Ok(pubkey) = PublicKey::load_from_file(&config.public_key_file) else {
+        eprintln!(
+            "Error reading public key from file {}",
+            config.public_key_file
+        );
+        exit(1);
+    };
+    let transport = TransportConfig {
+        encryption: config.encryption,
+        ..Default::default()
+    };
+    let connection = proxy::connect(&config.address, config.proxy.as_ref())
+        .and_then(|stream| Connection::connect(stream, &transport));
+    let mut connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {e}", config.address);
+            exit(1);
+        }
+    };
+
+    let result = if config.light {
+        light(&mut connection, config.params, &pubkey, &config.confirm)
+    } else {
+        balance(&mut connection, &pubkey)
+            .map(|(balance, pending)| println!("Balance: {balance} ({pending} pending)"))
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to sync with {}: {e}", config.address);
+        exit(1);
+    }
+}
+
+// trusts the node: it sees the key and reports what it likes
+fn balance<S: Read + Write>(
+    connection: &mut Connection<S>,
+    pubkey: &PublicKey,
+) -> IoResult<(Amount, Amount)> {
+    connection.send(&Message::FetchUTXO(pubkey.clone()))?;
+    let utxos = wait_for(connection, |message| match message {
+        Message::UTXOs(utxos) => Some(utxos),
+        _ => None,
+    })?;
+    // marked outputs are being spent by a mempool transaction
+    let sum = |marked: bool| {
+        Amount::checked_sum(
+            utxos
+                .iter()
+                .filter(|(_, spending)| *spending == marked)
+                .map(|(output, _)| output.value),
+        )
+        .ok_or_else(|| invalid("balance overflows"))
+    };
+    Ok((sum(false)?, sum(true)?))
+}
+
+fn light<S: Read + Write>(
+    connection: &mut Connection<S>,
+    params: ChainParams,
+    pubkey: &PublicKey,
+    confirm: &[Hash],
+) -> IoResult<()> {
+    let mut chain = HeaderChain::new(params);
+    sync_headers(connection, &mut chain)?;
+    println!("Synced {} headers", chain.height());
+    let utxos = scan(connection, &chain, pubkey)?;
+    let balance = Amount::checked_sum(utxos.values().map(|output| output.value))
+        .ok_or_else(|| invalid("balance overflows"))?;
+    println!("Balance: {balance}");
+    for txid in confirm {
+        match confirmation(connection, &chain, txid)? {
+            Some(height) => println!(
+                "{txid} confirmed at height {height}, {} confirmations",
+                chain.height() - height
+            ),
+            None => println!("{txid} not confirmed"),
+        }
+    }
+    Ok(())
+}
+
+/// Follow the node's headers to its tip, checking each one
+fn sync_headers<S: Read + Write>(
+    connection: &mut Connection<S>,
+    chain: &mut HeaderChain,
+) -> IoResult<()> {
+    loop {
+        connection.send(&Message::GetHeaders(chain.locator()))?;
+        let headers = wait_for(connection, |message| match message {
+            Message::Headers(headers) => Some(headers),
+            _ => None,
+        })?;
+        if headers.is_empty() {
+            return Ok(());
+        }
+        chain
+            .add_headers(headers)
+            .map_err(|e| invalid(&format!("invalid header: {e}")))?;
+    }
+}
+
+/// Our unspent outputs by output hash. Filters are matched here, so the
+/// node never learns the key; only matching blocks are downloaded, and
+/// those are checked against our headers.
+fn scan<S: Read + Write>(
+    connection: &mut Connection<S>,
+    chain: &HeaderChain,
+    pubkey: &PublicKey,
+) -> IoResult<HashMap<Hash, TransactionOutput>> {
+    let mut utxos = HashMap::new();
+    let mut prev_filter_header = Hash::zero();
+    let mut height = 0;
+    while height < chain.height() {
+        connection.send(&Message::GetFilterHeaders(height))?;
+        let filter_headers = wait_for(connection, |message| match message {
+            Message::FilterHeaders(headers) => Some(headers),
+            _ => None,
+        })?;
+        connection.send(&Message::GetFilters(height))?;
+        let filters = wait_for(connection, |message| match message {
+            Message::Filters(filters) => Some(filters),
+            _ => None,
+        })?;
+        if filters.is_empty() || filters.len() != filter_headers.len() {
+            return Err(invalid("missing filters"));
+        }
+
+        for ((block_hash, filter), filter_header) in filters.into_iter().zip(filter_headers) {
+            // with a single node this only catches inconsistent answers,
+            // an omitted match needs a second node to notice
+            let header = chain
+                .header(height)
+                .ok_or_else(|| invalid("too many filters"))?;
+            if block_hash != header.hash() || filter.header(&prev_filter_header) != filter_header {
+                return Err(invalid("filter does not match the chain"));
+            }
+            prev_filter_header = filter_header;
+
+            let outputs: Vec<Hash> = utxos.keys().copied().collect();
+            if filter.matches_any(&block_hash, std::slice::from_ref(pubkey), &outputs) {
+                let block = fetch_block(connection, height)?;
+                if block.hash() != block_hash
+                    || MerkleRoot::calculate(&block.transactions).ok() != Some(header.merkle_root)
+                {
+                    return Err(invalid("block does not match its header"));
+                }
+                apply_block(&mut utxos, &block, pubkey);
+            }
+            height += 1;
+        }
+    }
+    Ok(utxos)
+}
+
+fn fetch_block<S: Read + Write>(connection: &mut Connection<S>, height: u64) -> IoResult<Block> {
+    connection.send(&Message::FetchBlock(height as usize))?;
+    wait_for(connection, |message| match message {
+        Message::NewBlock(block) => Some(block),
+        _ => None,
+    })
+}
+
+// spend our outputs the block spends, add the ones it pays us
+fn apply_block(utxos: &mut HashMap<Hash, TransactionOutput>, block: &Block, pubkey: &PublicKey) {
+    for transaction in &block.transactions {
+        for input in &transaction.inputs {
+            utxos.remove(&input.prev_transaction_output_hash);
+        }
+        for output in &transaction.outputs {
+            if output.pubkey == *pubkey {
+                utxos.insert(output.hash(), output.clone());
+            }
+        }
+    }
+}
+
+/// Height of the block holding `txid`, proven against our own headers
+fn confirmation<S: Read + Write>(
+    connection: &mut Connection<S>,
+    chain: &HeaderChain,
+    txid: &Hash,
+) -> IoResult<Option<u64>> {
+    connection.send(&Message::FetchMerkleProof(*txid))?;
+    let proof = wait_for(connection, |message| match message {
+        Message::MerkleProof(proof) => Some(proof),
+        _ => None,
+    })?;
+    match proof {
+        Some((height, _, proof))
+            if proof.transaction == *txid && chain.verify_merkle_proof(height, &proof) =>
+        {
+            Ok(Some(height))
+        }
+        Some(_) => Err(invalid("invalid merkle proof")),
+        // a lying node can only hide a transaction, not make one up
+        None => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, message.to_string())
+}
+
+// answers pings while waiting; announcements meant for full nodes are skipped
+fn wait_for<S: Read + Write, T>(
+    connection: &mut Connection<S>,
+    wanted: impl Fn(Message) -> Option<T>,
+) -> IoResult<T> {
+    loop {
+        match connection.receive()? {
+            Message::Ping(nonce) => connection.send(&Message::Pong(nonce))?,
+            message => {
+                if let Some(value) = wanted(message) {
+                    return Ok(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Instant;
+
+    use btclib::crypto::{PrivateKey, Signature};
+    use btclib::node::{Action, Node};
+    use btclib::rpc::block_template;
+    use btclib::types::{Blockchain, Transaction, TransactionInput};
+
+    use super::*;
+
+    // a full node serving its peers one at a time
+    fn node_server(node: Arc<Mutex<Node>>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let deliver = |node: &mut Node, connection: &mut Connection<TcpStream>| {
+                for action in node.take_actions() {
+                    if let Action::Send(_, message) = action {
+                        connection.send(&message).unwrap();
+                    }
+                }
+            };
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let peer = stream.peer_addr().unwrap();
+                let config = TransportConfig::default();
+                let mut connection = Connection::accept(stream, &config).unwrap();
+                let mut locked = node.lock().unwrap();
+                locked.connected(peer, false, Instant::now());
+                deliver(&mut locked, &mut connection);
+                drop(locked);
+                while let Ok(message) = connection.receive() {
+                    let mut node = node.lock().unwrap();
+                    node.receive(peer, message, Instant::now());
+                    deliver(&mut node, &mut connection);
+                }
+            }
+        });
+        address
+    }
+
+    fn mine(node: &mut Node, pubkey: &PublicKey) {
+        let mut block = block_template(node.blockchain(), pubkey.clone()).unwrap();
+        while !block.header.mine(100_000) {}
+        node.submit_block(block).unwrap();
+    }
+
+    #[test]
+    fn light_scan_finds_own_outputs() {
+        let key = PrivateKey::new_key();
+        let pubkey = key.public_key();
+        let other = PrivateKey::new_key().public_key();
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        for _ in 0..3 {
+            mine(&mut node, &pubkey);
+        }
+        // send the first coinbase elsewhere, in a block paying someone else
+        let output = node.blockchain().blocks().nth(1).unwrap().transactions[0].outputs[0].clone();
+        let spend = Transaction::new(
+            vec![TransactionInput {
+                prev_transaction_output_hash: output.hash(),
+                signature: Signature::sign_output_for(&output.hash(), &key, &output.pubkey),
+            }],
+            vec![TransactionOutput {
+                pubkey: other.clone(),
+                ..output.clone()
+            }],
+        );
+        node.submit_transaction(spend, Instant::now()).unwrap();
+        mine(&mut node, &other);
+        let address = node_server(Arc::new(Mutex::new(node)));
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut connection = Connection::connect(stream, &TransportConfig::default()).unwrap();
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        sync_headers(&mut connection, &mut chain).unwrap();
+        let utxos = scan(&mut connection, &chain, &pubkey).unwrap();
+        assert_eq!(utxos.len(), 2);
+        assert!(!utxos.contains_key(&output.hash()));
+        assert!(utxos.values().all(|output| output.pubkey == pubkey));
+        assert_eq!(scan(&mut connection, &chain, &other).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn light_sync_proves_transactions() {
+        let pubkey = PrivateKey::new_key().public_key();
+        let mut node = Node::new(Blockchain::new(ChainParams::regtest()));
+        for _ in 0..3 {
+            mine(&mut node, &pubkey);
+        }
+        let coinbase = node.blockchain().blocks().nth(2).unwrap().transactions[0].hash();
+        let address = node_server(Arc::new(Mutex::new(node)));
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut connection = Connection::connect(stream, &TransportConfig::default()).unwrap();
+        let mut chain = HeaderChain::new(ChainParams::regtest());
+        sync_headers(&mut connection, &mut chain).unwrap();
+        assert_eq!(chain.height(), 4);
+        assert_eq!(
+            confirmation(&mut connection, &chain, &coinbase).unwrap(),
+            Some(2)
+        );
+        assert_eq!(
+            confirmation(&mut connection, &chain, &Hash::zero()).unwrap(),
+            None
+        );
+
+        // the full mode just asks
+        let (balance, pending) = balance(&mut connection, &pubkey).unwrap();
+        assert_eq!(balance.to_sat(), 3 * 50 * 100_000_000);
+        assert_eq!(pending, Amount::ZERO);
+    }
 }